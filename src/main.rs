@@ -1,25 +1,62 @@
 use anyhow::{anyhow, Context, Result};
-use clap::{Parser, Subcommand};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::engine::{ArgValueCompleter, CompletionCandidate};
+use clap_complete::env::{CompleteEnv, Shells};
 use crossterm::{
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, is_raw_mode_enabled, Clear, ClearType},
-    event::{self, Event, KeyCode},
+    event::{self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEventKind, KeyModifiers},
     cursor::{Hide, Show, MoveTo, EnableBlinking, DisableBlinking},
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::BTreeMap;
 use std::fs;
-use std::io::{self, Write};
-use std::path::PathBuf;
-use std::process::Command;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use zeroize::{Zeroize, Zeroizing};
 
 const PROVIDERS_DIR: &str = "providers";
+const PROVIDER_SCHEMA_VERSION: u32 = 2;
+/// File names of Claude Code's own configuration, and ours, which must never be read as providers.
+const RESERVED_PROVIDER_NAMES: &[&str] = &["settings", "config", "credentials", "claude-provider"];
 const SETTINGS_FILE: &str = "settings.json";
+const TOOL_CONFIG_FILE: &str = "claude-provider.json";
+const JOURNAL_FILE: &str = "claude-provider.journal";
+const VAULT_FILE: &str = "claude-provider.vault";
+const BUNDLE_FORMAT: &str = "claude-provider-bundle";
+const BUNDLE_VERSION: u32 = 1;
+const VAULT_VERSION: u32 = 1;
+/// Binds the ciphertext to this file format so it can't be replayed into another context.
+const VAULT_AAD: &[u8] = b"claude-provider vault v1";
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Sent with every request `test` and model discovery make to a provider.
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Used by `test` when a provider doesn't configure any model.
+const FALLBACK_TEST_MODEL: &str = "claude-sonnet-4-5";
+const API_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a forwarded termination signal may take to stop `claude` before we restore and exit anyway.
+const SIGNAL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
 enum Shell {
     Bash,
     Zsh,
+    Fish,
+    #[value(alias = "nu")]
+    Nushell,
 }
 
 fn detect_shell() -> Shell {
@@ -28,6 +65,8 @@ fn detect_shell() -> Shell {
         .and_then(|s| {
             if s.contains("zsh") {
                 Some(Shell::Zsh)
+            } else if s.contains("fish") {
+                Some(Shell::Fish)
             } else if s.contains("bash") {
                 Some(Shell::Bash)
             } else {
@@ -44,62 +83,368 @@ fn detect_shell() -> Shell {
 }
 
 impl Shell {
-    fn func_file_name(&self) -> &str {
+    fn name(&self) -> &'static str {
+        match self {
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Fish => "fish",
+            Shell::Nushell => "nushell",
+        }
+    }
+
+    /// Fish sources everything in conf.d on startup, so its file needs no rc line.
+    fn func_path(&self) -> Result<PathBuf> {
         match self {
-            Shell::Bash => "provider-functions.bash",
-            Shell::Zsh => "provider-functions.zsh",
+            Shell::Bash => Ok(get_config_dir()?.join("provider-functions.bash")),
+            Shell::Zsh => Ok(get_config_dir()?.join("provider-functions.zsh")),
+            Shell::Fish => Ok(fish_config_dir().join("conf.d").join("claude-provider.fish")),
+            Shell::Nushell => Err(anyhow!("nushell functions only come from 'claude-provider init nushell'")),
         }
     }
 
-    fn rc_file_name(&self) -> &str {
+    fn rc_file_name(&self) -> Option<&str> {
         match self {
-            Shell::Bash => ".bashrc",
-            Shell::Zsh => ".zshrc",
+            Shell::Bash => Some(".bashrc"),
+            Shell::Zsh => Some(".zshrc"),
+            Shell::Fish | Shell::Nushell => None,
         }
     }
 
     fn source_command(&self, path: &std::path::Path) -> String {
+        format!("source {}", path.display())
+    }
+
+    fn function_definition(&self, name: &str) -> String {
         match self {
-            Shell::Bash => format!("source {}", path.display()),
-            Shell::Zsh => format!("source {}", path.display()),
+            Shell::Bash | Shell::Zsh => format!(
+                r#"# Provider function for {name}
+{name}() {{
+    claude-provider use {name} "$@"
+}}
+"#
+            ),
+            Shell::Fish => format!(
+                r#"# Provider function for {name}
+function {name} --wraps claude --description 'Claude Code with the {name} provider'
+    claude-provider use {name} $argv
+end
+"#
+            ),
+            Shell::Nushell => format!(
+                r#"# Provider function for {name}
+def --wrapped {name} [...args] {{
+    claude-provider use {name} ...$args
+}}
+"#
+            ),
         }
     }
 }
 
+fn fish_config_dir() -> PathBuf {
+    match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("fish"),
+        None => dirs::home_dir().unwrap_or_default().join(".config").join("fish"),
+    }
+}
+
+/// Shells that get provider functions: bash and zsh always, fish once it is set up or in use.
+fn integrated_shells() -> Vec<Shell> {
+    let mut shells = vec![Shell::Bash, Shell::Zsh];
+    if fish_config_dir().is_dir() || detect_shell() == Shell::Fish {
+        shells.push(Shell::Fish);
+    }
+    shells
+}
+
 #[derive(Serialize, Deserialize, Default, Debug)]
 struct EnvSettings {
-    #[serde(alias = "anthropic_base_url")]
     anthropic_base_url: Option<String>,
-    #[serde(alias = "anthropic_auth_token")]
     anthropic_auth_token: Option<String>,
-    #[serde(alias = "api_timeout_ms")]
     api_timeout_ms: Option<String>,
-    #[serde(alias = "claude_code_disable_nonessential_traffic")]
     claude_code_disable_nonessential_traffic: Option<i32>,
-    #[serde(alias = "anthropic_model")]
     anthropic_model: Option<String>,
-    #[serde(alias = "anthropic_small_fast_model")]
     anthropic_small_fast_model: Option<String>,
-    #[serde(alias = "anthropic_default_sonnet_model")]
     anthropic_default_sonnet_model: Option<String>,
-    #[serde(alias = "anthropic_default_opus_model")]
     anthropic_default_opus_model: Option<String>,
-    #[serde(alias = "anthropic_default_haiku_model")]
     anthropic_default_haiku_model: Option<String>,
+    /// Any other variables, stored under their real names (e.g. `HTTPS_PROXY`)
+    #[serde(flatten, deserialize_with = "deserialize_env_extra")]
+    extra: BTreeMap<String, String>,
+}
+
+/// Hand-written files often give numbers and booleans unquoted (`"MAX_THINKING_TOKENS": 1024`);
+/// they become the same strings `capture` makes of them.
+fn deserialize_env_extra<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, String>, D::Error> {
+    use serde::de::Error;
+    BTreeMap::<String, Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| match value {
+            Value::String(_) | Value::Number(_) | Value::Bool(_) => Ok((key, env_value_to_string(&value))),
+            _ => Err(D::Error::custom(format!("env variable '{}' must be a string, number or boolean", key))),
+        })
+        .collect()
+}
+
+impl EnvSettings {
+    /// The key Claude Code authenticates with: the auth token, or else an `ANTHROPIC_API_KEY` kept as
+    /// an extra variable (as `capture` does for settings.json files that use it).
+    fn api_key(&self) -> &str {
+        match self.anthropic_auth_token.as_deref() {
+            Some(token) if !token.trim().is_empty() => token,
+            _ => self.extra.get("ANTHROPIC_API_KEY").map(String::as_str).unwrap_or_default(),
+        }
+    }
+}
+
+/// The model variables Claude Code reads, one per role it picks a model for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ModelSlot {
+    Default,
+    Sonnet,
+    Opus,
+    Haiku,
+    SmallFast,
+}
+
+impl ModelSlot {
+    const ALL: [ModelSlot; 5] = [ModelSlot::Default, ModelSlot::Sonnet, ModelSlot::Opus, ModelSlot::Haiku, ModelSlot::SmallFast];
+
+    fn label(self) -> &'static str {
+        match self {
+            ModelSlot::Default => "Default model",
+            ModelSlot::Sonnet => "Sonnet model",
+            ModelSlot::Opus => "Opus model",
+            ModelSlot::Haiku => "Haiku model",
+            ModelSlot::SmallFast => "Small/fast model",
+        }
+    }
+
+    fn env_var(self) -> &'static str {
+        match self {
+            ModelSlot::Default => "ANTHROPIC_MODEL",
+            ModelSlot::Sonnet => "ANTHROPIC_DEFAULT_SONNET_MODEL",
+            ModelSlot::Opus => "ANTHROPIC_DEFAULT_OPUS_MODEL",
+            ModelSlot::Haiku => "ANTHROPIC_DEFAULT_HAIKU_MODEL",
+            ModelSlot::SmallFast => "ANTHROPIC_SMALL_FAST_MODEL",
+        }
+    }
+
+    fn get(self, env: &EnvSettings) -> Option<&str> {
+        match self {
+            ModelSlot::Default => env.anthropic_model.as_deref(),
+            ModelSlot::Sonnet => env.anthropic_default_sonnet_model.as_deref(),
+            ModelSlot::Opus => env.anthropic_default_opus_model.as_deref(),
+            ModelSlot::Haiku => env.anthropic_default_haiku_model.as_deref(),
+            ModelSlot::SmallFast => env.anthropic_small_fast_model.as_deref(),
+        }
+    }
+
+    fn get_mut(self, env: &mut EnvSettings) -> &mut Option<String> {
+        match self {
+            ModelSlot::Default => &mut env.anthropic_model,
+            ModelSlot::Sonnet => &mut env.anthropic_default_sonnet_model,
+            ModelSlot::Opus => &mut env.anthropic_default_opus_model,
+            ModelSlot::Haiku => &mut env.anthropic_default_haiku_model,
+            ModelSlot::SmallFast => &mut env.anthropic_small_fast_model,
+        }
+    }
+
+    /// Slots this one defaults to, in order: sonnet and opus follow the default model, and the
+    /// background small/fast model follows haiku so it doesn't end up on the expensive one.
+    fn follows(self) -> &'static [ModelSlot] {
+        match self {
+            ModelSlot::Sonnet | ModelSlot::Opus => &[ModelSlot::Default],
+            ModelSlot::SmallFast => &[ModelSlot::Haiku, ModelSlot::Default],
+            ModelSlot::Default | ModelSlot::Haiku => &[],
+        }
+    }
+
+    /// The slot and model this slot would default to.
+    fn inherited(self, env: &EnvSettings) -> Option<(ModelSlot, String)> {
+        self.follows()
+            .iter()
+            .find_map(|slot| slot.get(env).filter(|m| !m.is_empty()).map(|m| (*slot, m.to_string())))
+    }
+
+    /// Problems that make a model ID unusable for this slot.
+    fn issue(self, model: &str) -> Option<String> {
+        if model.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Some(format!("{} '{}' must not contain whitespace", self.label(), model));
+        }
+        None
+    }
+
+    /// Choices that work but are probably mistakes, such as an opus model for background tasks.
+    fn warning(self, model: &str) -> Option<String> {
+        let lower = model.to_ascii_lowercase();
+        let family = ["opus", "sonnet", "haiku"].into_iter().find(|f| lower.contains(f))?;
+        match self {
+            ModelSlot::SmallFast if family != "haiku" => {
+                Some(format!("{} runs background tasks; '{}' is probably an expensive choice", self.label(), model))
+            }
+            ModelSlot::Sonnet | ModelSlot::Opus | ModelSlot::Haiku
+                if !self.label().to_ascii_lowercase().starts_with(family) =>
+            {
+                Some(format!("{} '{}' looks like a model for the {} slot", self.label(), model, family))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How `use` hands the provider's configuration to `claude`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum LaunchMode {
+    /// Pass the provider env to the child process, leaving settings.json untouched
+    #[default]
+    Env,
+    /// Swap the provider env into settings.json for the session and restore it afterwards
+    Settings,
+}
+
+impl LaunchMode {
+    fn as_str(&self) -> &str {
+        match self {
+            LaunchMode::Env => "env",
+            LaunchMode::Settings => "settings",
+        }
+    }
+}
+
+/// How the provider env is combined with the `env` already present in `settings.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum EnvMergeStrategy {
+    /// Drop the existing env entirely and use only the provider's variables
+    Replace,
+    /// Keep the existing env; provider variables win on conflicts
+    #[default]
+    Merge,
+    /// Keep the existing env; existing variables win on conflicts
+    MergePreserve,
+}
+
+impl EnvMergeStrategy {
+    fn as_str(&self) -> &str {
+        match self {
+            EnvMergeStrategy::Replace => "replace",
+            EnvMergeStrategy::Merge => "merge",
+            EnvMergeStrategy::MergePreserve => "merge-preserve",
+        }
+    }
+}
+
+/// What is echoed while typing a secret.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum SecretFeedback {
+    /// Echo nothing
+    #[default]
+    Hidden,
+    /// Echo one '*' per character
+    Asterisks,
+}
+
+impl SecretFeedback {
+    fn as_str(&self) -> &str {
+        match self {
+            SecretFeedback::Hidden => "hidden",
+            SecretFeedback::Asterisks => "asterisks",
+        }
+    }
+}
+
+/// What `export` writes in place of plaintext secrets.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+enum TokenExport {
+    /// Copy secrets as they are
+    #[default]
+    Keep,
+    /// Leave secrets empty; `import` asks for them
+    Redact,
+    /// Replace secrets with env: references such as env:ZAI_AUTH_TOKEN
+    Env,
+}
+
+/// What `import` does with a provider whose name is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum ImportConflict {
+    Skip,
+    Overwrite,
+    /// Import under the first free name-N
+    Rename,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ClaudeSettings {
+    /// Version of the provider file format; see `PROVIDER_MIGRATIONS`
     #[serde(default)]
-    env: EnvSettings,
+    schema_version: u32,
     #[serde(default)]
+    env: EnvSettings,
+    #[serde(default, alias = "enabledPlugins")]
     enabled_plugins: Value,
-    #[serde(default)]
+    #[serde(default, alias = "alwaysThinkingEnabled")]
     always_thinking_enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    launch_mode: Option<LaunchMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    env_merge: Option<EnvMergeStrategy>,
     #[serde(flatten)]
     other: Value,
 }
 
+/// Global preferences for claude-provider itself, stored next to the providers.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct ToolConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    launch_mode: Option<LaunchMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    env_merge: Option<EnvMergeStrategy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret_feedback: Option<SecretFeedback>,
+    /// Seconds to reuse `cmd:` secret output within a login session; unset disables the cache
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret_cache_ttl: Option<u64>,
+    /// Key file that unlocks the vault instead of a passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vault_key_file: Option<PathBuf>,
+    /// Where provider files live instead of `~/.claude/providers`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    providers_dir: Option<String>,
+    /// Keep a copy of provider files before upgrading their schema; on when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    migration_backups: Option<bool>,
+    /// Write function files and rc source lines; off when the functions come from `init`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shell_function_files: Option<bool>,
+}
+
+impl ToolConfig {
+    fn path() -> Result<PathBuf> {
+        Ok(get_config_dir()?.join(TOOL_CONFIG_FILE))
+    }
+
+    fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&path)?;
+        serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(Self::path()?, content)?;
+        Ok(())
+    }
+}
+
 fn get_config_dir() -> Result<PathBuf> {
     let claude_dir = PathBuf::from(env!("HOME")).join(".claude");
     if !claude_dir.exists() {
@@ -108,20 +453,82 @@ fn get_config_dir() -> Result<PathBuf> {
     Ok(claude_dir)
 }
 
-fn get_providers_dir() -> PathBuf {
-    get_config_dir().unwrap_or_else(|_| {
-        PathBuf::from(env!("HOME")).join(".claude").join(PROVIDERS_DIR)
-    })
+fn get_providers_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("CLAUDE_PROVIDER_DIR").filter(|dir| !dir.is_empty()) {
+        return Ok(expand_home(&dir.to_string_lossy()));
+    }
+    match ToolConfig::load()?.providers_dir {
+        Some(dir) => Ok(expand_home(&dir)),
+        None => Ok(get_config_dir()?.join(PROVIDERS_DIR)),
+    }
 }
 
 fn ensure_providers_dir() -> Result<PathBuf> {
-    let dir = get_providers_dir();
+    let dir = get_providers_dir()?;
     if !dir.exists() {
         fs::create_dir_all(&dir)?;
+        migrate_legacy_providers(&dir)?;
     }
     Ok(dir)
 }
 
+/// Older versions kept providers directly in `~/.claude`, next to Claude Code's own files. Only
+/// files that look like something we wrote are moved: their `"env"` uses our snake_case field names,
+/// while Claude Code's settings use the real variable names.
+fn is_legacy_provider_file(path: &Path) -> bool {
+    let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    if path.extension().and_then(|e| e.to_str()) != Some("json") || validate_provider_name(name).is_err() {
+        return false;
+    }
+    let Some(value) = fs::read_to_string(path).ok().and_then(|c| serde_json::from_str::<Value>(&c).ok()) else {
+        return false;
+    };
+    value.get("schema_version").is_some()
+        || value
+            .get("env")
+            .and_then(Value::as_object)
+            .is_some_and(|env| env.keys().any(|key| ENV_FIELDS.contains(&key.as_str())))
+}
+
+/// Runs once, when the providers directory is first created.
+fn migrate_legacy_providers(providers_dir: &Path) -> Result<()> {
+    let config_dir = get_config_dir()?;
+    if providers_dir == config_dir {
+        return Ok(());
+    }
+
+    let mut moved = Vec::new();
+    for entry in fs::read_dir(&config_dir)? {
+        let path = entry?.path();
+        if !path.is_file() || !is_legacy_provider_file(&path) {
+            continue;
+        }
+        let Some(file_name) = path.file_name() else { continue };
+        let target = providers_dir.join(file_name);
+        // Providers can hold tokens; a rename keeps the permissions, a copy across filesystems may not.
+        if fs::rename(&path, &target).is_err() {
+            fs::copy(&path, &target)?;
+            set_private_permissions(&target)?;
+            fs::remove_file(&path)?;
+        }
+        moved.push(file_name.to_string_lossy().into_owned());
+    }
+
+    if !moved.is_empty() {
+        moved.sort();
+        eprintln!(
+            "  ✓ Moved {} provider(s) from {} to {}: {}",
+            moved.len(),
+            config_dir.display(),
+            providers_dir.display(),
+            moved.join(", ")
+        );
+    }
+    Ok(())
+}
+
 fn clear_screen() {
     let mut stdout = io::stdout();
     
@@ -179,8 +586,18 @@ fn wait_for_key() {
 
 fn draw_menu_with_arrows(options: &[&str], title: &str) -> usize {
     let mut selected = 0;
+    let mut offset = 0;
 
     loop {
+        // Long lists (e.g. a provider's models) scroll instead of running off the screen.
+        let rows = crossterm::terminal::size().ok().map(|(_, rows)| rows as usize).filter(|&r| r > 0).unwrap_or(24);
+        let visible = rows.saturating_sub(9).max(3);
+        if selected < offset {
+            offset = selected;
+        } else if selected >= offset + visible {
+            offset = selected + 1 - visible;
+        }
+
         clear_screen();
 
         
@@ -200,32 +617,31 @@ fn draw_menu_with_arrows(options: &[&str], title: &str) -> usize {
         stdout.flush().unwrap();
 
         
-        for (i, opt) in options.iter().enumerate() {
+        for (row, (i, opt)) in options.iter().enumerate().skip(offset).take(visible).enumerate() {
             let marker = if i == selected { ">" } else { " " };
-            let y = i as u16 + 6;
+            let y = row as u16 + 6;
             execute!(stdout, MoveTo(0, y)).unwrap();
             execute!(stdout, Clear(ClearType::UntilNewLine)).unwrap();
             println!("  {}  {}", marker, opt);
         }
 
         
-        let y = (options.len() as u16) + 8;
+        let y = (options.len().min(visible) as u16) + 8;
         execute!(stdout, MoveTo(0, y)).unwrap();
         execute!(stdout, Clear(ClearType::UntilNewLine)).unwrap();
+        if options.len() > visible {
+            print!("  ({}/{}) ", selected + 1, options.len());
+        }
         print!("  Use ↑/↓ arrows to navigate, Enter to select, Esc to go back");
         stdout.flush().unwrap();
 
         if let Ok(Event::Key(key)) = event::read() {
             match key.code {
                 KeyCode::Up | KeyCode::Char('k') => {
-                    if selected > 0 {
-                        selected -= 1;
-                    }
+                    selected = selected.saturating_sub(1);
                 }
-                KeyCode::Down | KeyCode::Char('j') => {
-                    if selected < options.len() - 1 {
-                        selected += 1;
-                    }
+                KeyCode::Down | KeyCode::Char('j') if selected + 1 < options.len() => {
+                    selected += 1;
                 }
                 KeyCode::Enter => {
                     return selected;
@@ -239,27 +655,151 @@ fn draw_menu_with_arrows(options: &[&str], title: &str) -> usize {
     }
 }
 
+/// Reads a line without echoing it, handling backspace, Ctrl-U and bracketed paste. Falls back to a
+/// plain line read when stdin is not a terminal so piped input keeps working.
 fn prompt_password(prompt: &str) -> Result<String> {
-    prompt_input(prompt)
+    if !io::stdin().is_terminal() {
+        return prompt_input(prompt);
+    }
+
+    let feedback = ToolConfig::load().map(|c| c.secret_feedback.unwrap_or_default()).unwrap_or_default();
+    let mut stdout = io::stdout();
+    print!("{}", prompt);
+    stdout.flush()?;
+
+    let was_raw = is_raw_mode_enabled().unwrap_or(false);
+    enable_raw_mode()?;
+    let _ = execute!(stdout, EnableBracketedPaste);
+
+    let mut secret = String::new();
+    let result = loop {
+        let typed = match event::read() {
+            Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => match key.code {
+                KeyCode::Enter => break Ok(()),
+                KeyCode::Esc => break Err(anyhow!("Cancelled")),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    break Err(anyhow!("Cancelled"));
+                }
+                KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    if feedback == SecretFeedback::Asterisks {
+                        print!("{}", "\u{8} \u{8}".repeat(secret.chars().count()));
+                    }
+                    secret.clear();
+                    String::new()
+                }
+                KeyCode::Backspace => {
+                    if secret.pop().is_some() && feedback == SecretFeedback::Asterisks {
+                        print!("\u{8} \u{8}");
+                    }
+                    String::new()
+                }
+                KeyCode::Char(c) => c.to_string(),
+                _ => String::new(),
+            },
+            Ok(Event::Paste(text)) => text.trim_end_matches(['\r', '\n']).to_string(),
+            Ok(_) => String::new(),
+            Err(e) => break Err(e.into()),
+        };
+
+        secret.push_str(&typed);
+        if feedback == SecretFeedback::Asterisks {
+            print!("{}", "*".repeat(typed.chars().count()));
+        }
+        stdout.flush()?;
+    };
+
+    let _ = execute!(stdout, DisableBracketedPaste);
+    if !was_raw {
+        disable_raw_mode().ok();
+    }
+    print!("\r\n");
+    stdout.flush()?;
+
+    result.map(|_| secret.trim().to_string())
 }
 
-fn remove_provider_function_from_file(func_path: &PathBuf, provider_name: &str) -> Result<()> {
-    if !func_path.exists() {
-        return Ok(());
+/// Shows just enough of a secret to recognise it: the last 4 characters, and only for longer values.
+fn secret_hint(secret: &str) -> String {
+    let len = secret.chars().count();
+    if len >= 8 {
+        let tail: String = secret.chars().skip(len - 4).collect();
+        format!("…{} ({} characters)", tail, len)
+    } else {
+        format!("({} characters)", len)
+    }
+}
+
+/// Masked prompt for an API key, followed by a confirmation showing only its last characters.
+/// An empty answer returns an empty string so callers can treat it as "keep" or "missing".
+fn prompt_secret(prompt: &str) -> Result<String> {
+    loop {
+        let secret = prompt_password(prompt)?;
+        if secret.is_empty() || !io::stdin().is_terminal() {
+            return Ok(secret);
+        }
+        let answer = prompt_input(&format!("  Key entered: {}. Correct? [Y/n] ", secret_hint(&secret)))?;
+        if !(answer.eq_ignore_ascii_case("n") || answer.eq_ignore_ascii_case("no")) {
+            return Ok(secret);
+        }
+    }
+}
+
+fn parse_env_assignment(input: &str) -> Result<(String, String)> {
+    let (key, value) = input
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected KEY=VALUE, got '{}'", input))?;
+    let key = key.trim();
+
+    let mut chars = key.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(anyhow!("'{}' is not a valid environment variable name", key));
+    }
+
+    Ok((key.to_string(), value.trim().to_string()))
+}
+
+fn prompt_extra_env(extra: &mut BTreeMap<String, String>) -> Result<()> {
+    if !extra.is_empty() {
+        println!("  Current extra environment variables:");
+        for (key, value) in extra.iter() {
+            println!("    {}={}", key, value);
+        }
+    }
+    println!("  Extra environment variables as KEY=VALUE (KEY= removes one), empty line to finish:");
+
+    loop {
+        let line = prompt_input("    ")?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        match parse_env_assignment(&line) {
+            Ok((key, value)) if value.is_empty() => {
+                extra.remove(&key);
+            }
+            Ok((key, value)) => {
+                extra.insert(key, value);
+            }
+            Err(e) => println!("    ✗ {}", e),
+        }
     }
+}
 
-    let existing_funcs = fs::read_to_string(func_path)?;
+/// Drops the function block generated for `provider_name`, which runs from its marker comment to the
+/// closing `}` (bash, zsh) or `end` (fish).
+fn strip_provider_function(content: &str, provider_name: &str) -> String {
     let marker = format!("# Provider function for {}", provider_name);
     let mut cleaned_lines = Vec::new();
     let mut in_function_block = false;
 
-    for line in existing_funcs.lines() {
-        if line.starts_with(&marker) {
+    for line in content.lines() {
+        if line.trim_end() == marker {
             in_function_block = true;
             continue;
         }
         if in_function_block {
-            if line.trim() == "}" {
+            if matches!(line.trim(), "}" | "end") {
                 in_function_block = false;
             }
             continue;
@@ -267,108 +807,102 @@ fn remove_provider_function_from_file(func_path: &PathBuf, provider_name: &str)
         cleaned_lines.push(line);
     }
 
-    let cleaned = cleaned_lines.join("\n").trim().to_string();
+    cleaned_lines.join("\n").trim().to_string()
+}
+
+fn remove_provider_function_from_file(func_path: &Path, provider_name: &str) -> Result<()> {
+    if !func_path.exists() {
+        return Ok(());
+    }
+
+    let cleaned = strip_provider_function(&fs::read_to_string(func_path)?, provider_name);
     if cleaned.is_empty() {
         fs::remove_file(func_path)?;
     } else {
-        fs::write(func_path, cleaned)?;
+        fs::write(func_path, format!("{}\n", cleaned))?;
     }
     Ok(())
 }
 
 fn remove_provider_function(provider_name: &str) -> Result<()> {
-    let config_dir = get_config_dir()?;
-    let shells = [Shell::Bash, Shell::Zsh];
-
-    for shell in &shells {
-        let func_path = config_dir.join(shell.func_file_name());
-        remove_provider_function_from_file(&func_path, provider_name)?;
+    for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+        remove_provider_function_from_file(&shell.func_path()?, provider_name)?;
     }
 
     Ok(())
 }
 
-fn append_provider_function_to_file(func_path: &PathBuf, rc_path: &PathBuf, name: &str, shell: Shell) -> Result<()> {
-    let func_content = format!(
-        r#"# Provider function for {name}
-{name}() {{
-    claude-provider use {name} "$@"
-}}
-"#,
-        name = name
-    );
-
+fn append_provider_function_to_file(func_path: &Path, rc_path: Option<&Path>, name: &str, shell: Shell) -> Result<()> {
     let existing = if func_path.exists() {
         fs::read_to_string(func_path)?
     } else {
         String::new()
     };
 
-    let marker = format!("# Provider function for {}", name);
-    let mut cleaned_lines = Vec::new();
-    let mut in_function_block = false;
-    for line in existing.lines() {
-        if line.starts_with(&marker) {
-            in_function_block = true;
-            continue;
-        }
-        if in_function_block {
-            if line.trim() == "}" {
-                in_function_block = false;
-            }
-            continue;
-        }
-        cleaned_lines.push(line);
-    }
-
-    let cleaned = cleaned_lines.join("\n").trim().to_string();
+    let cleaned = strip_provider_function(&existing, name);
+    let func_content = shell.function_definition(name);
     let new_content = if cleaned.is_empty() {
         func_content
     } else {
         format!("{}\n\n{}", cleaned, func_content)
     };
 
+    if let Some(dir) = func_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
     fs::write(func_path, new_content)?;
 
+    let (Some(rc_path), Some(rc_file_name)) = (rc_path, shell.rc_file_name()) else {
+        return Ok(());
+    };
     let source_line = shell.source_command(func_path);
 
     if rc_path.exists() {
         let rc_content = fs::read_to_string(rc_path)?;
         if !rc_content.contains(&source_line) {
             fs::write(rc_path, format!("{}\n{}\n", rc_content.trim(), source_line))?;
-            println!("  ✓ Added source line to ~/{}", shell.rc_file_name());
+            println!("  ✓ Added source line to ~/{}", rc_file_name);
         }
     } else {
         fs::write(rc_path, format!("{}\n", source_line))?;
-        println!("  ✓ Created ~/{} with source line", shell.rc_file_name());
+        println!("  ✓ Created ~/{} with source line", rc_file_name);
     }
 
     Ok(())
 }
 
 fn append_provider_function(name: &str) -> Result<()> {
-    let config_dir = get_config_dir()?;
-    let home = PathBuf::from(env!("HOME"));
+    if !ToolConfig::load()?.shell_function_files.unwrap_or(true) {
+        return Ok(());
+    }
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("Could not determine the home directory"))?;
 
-    for shell in [Shell::Bash, Shell::Zsh] {
-        let func_path = config_dir.join(shell.func_file_name());
-        let rc_path = home.join(shell.rc_file_name());
-        append_provider_function_to_file(&func_path, &rc_path, name, shell)?;
+    for shell in integrated_shells() {
+        let rc_path = shell.rc_file_name().map(|rc| home.join(rc));
+        append_provider_function_to_file(&shell.func_path()?, rc_path.as_deref(), name, shell)?;
     }
 
     Ok(())
 }
 
 fn list_providers() -> Result<Vec<String>> {
-    let dir = ensure_providers_dir()?;
+    provider_names_in(&ensure_providers_dir()?)
+}
+
+/// Providers in `dir` without creating it (and so without migrating legacy files into it).
+fn provider_names_in(dir: &Path) -> Result<Vec<String>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
     let mut providers = Vec::new();
 
     for entry in fs::read_dir(dir)? {
         let entry = entry?;
-        if entry.path().extension().and_then(|e| e.to_str()) == Some("json") {
-            if let Some(name) = entry.path().file_stem().and_then(|n| n.to_str()) {
-                providers.push(name.to_string());
-            }
+        if entry.path().extension().and_then(|e| e.to_str()) == Some("json")
+            && let Some(name) = entry.path().file_stem().and_then(|n| n.to_str())
+            && validate_provider_name(name).is_ok()
+        {
+            providers.push(name.to_string());
         }
     }
 
@@ -376,59 +910,481 @@ fn list_providers() -> Result<Vec<String>> {
     Ok(providers)
 }
 
-fn setup_provider_interactive() -> Result<()> {
-    println!();
-    println!("  ▸ Configure a new Claude Code provider");
-    println!();
+fn provider_path(name: &str) -> Result<PathBuf> {
+    Ok(ensure_providers_dir()?.join(format!("{}.json", name)))
+}
 
-    let name = prompt_input("  Enter provider name (e.g., minimax, zai): ")?;
+/// Provider names double as shell function names, so keep them to a portable subset.
+fn validate_provider_name(name: &str) -> Result<()> {
     if name.is_empty() {
         return Err(anyhow!("Provider name cannot be empty"));
     }
+    if name.starts_with('-') || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(anyhow!(
+            "Invalid provider name '{}': use letters, digits, '-' and '_' and don't start with '-'",
+            name
+        ));
+    }
+    if RESERVED_PROVIDER_NAMES.contains(&name) {
+        return Err(anyhow!("'{}' is reserved and can't be used as a provider name", name));
+    }
+    Ok(())
+}
 
-    let base_url = prompt_input("  Enter API base URL: ")?;
+fn base_url_issue(base_url: &str) -> Option<String> {
     if base_url.is_empty() {
-        return Err(anyhow!("Base URL cannot be empty"));
+        return Some("Base URL cannot be empty".to_string());
+    }
+    let Some(rest) = base_url.strip_prefix("https://").or_else(|| base_url.strip_prefix("http://")) else {
+        return Some(format!("Base URL '{}' must start with http:// or https://", base_url));
+    };
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    if host.is_empty() || base_url.chars().any(char::is_whitespace) {
+        return Some(format!("Base URL '{}' is not a valid URL", base_url));
     }
+    None
+}
 
-    let api_key = prompt_password("  Enter API key: ")?;
-    if api_key.is_empty() {
-        return Err(anyhow!("API key cannot be empty"));
+/// Every problem with a parsed provider, so callers can report them all at once.
+fn provider_settings_issues(settings: &ClaudeSettings) -> Vec<String> {
+    let mut issues = Vec::new();
+    if let Some(issue) = base_url_issue(settings.env.anthropic_base_url.as_deref().unwrap_or_default()) {
+        issues.push(issue);
+    }
+    if settings.env.api_key().trim().is_empty() {
+        issues.push("API key cannot be empty".to_string());
     }
+    for slot in ModelSlot::ALL {
+        issues.extend(slot.get(&settings.env).and_then(|model| slot.issue(model)));
+    }
+    issues
+}
 
-    let default_model = prompt_input("  Enter default model (for sonnet/opus/small_fast): ")?;
-    let haiku_model = prompt_input("  Enter haiku model (optional, press Enter to skip): ")?;
+fn validate_provider_settings(settings: &ClaudeSettings) -> Result<()> {
+    match provider_settings_issues(settings).into_iter().next() {
+        Some(issue) => Err(anyhow!(issue)),
+        None => Ok(()),
+    }
+}
 
-    let env = EnvSettings {
-        anthropic_base_url: Some(base_url),
-        anthropic_auth_token: Some(api_key),
-        api_timeout_ms: Some("3000000".to_string()),
-        claude_code_disable_nonessential_traffic: Some(1),
-        anthropic_model: Some(default_model.clone()).filter(|s| !s.is_empty()),
-        anthropic_small_fast_model: Some(default_model.clone()).filter(|s| !s.is_empty()),
-        anthropic_default_sonnet_model: Some(default_model.clone()).filter(|s| !s.is_empty()),
-        anthropic_default_opus_model: Some(default_model.clone()).filter(|s| !s.is_empty()),
-        anthropic_default_haiku_model: Some(haiku_model).filter(|s| !s.is_empty()),
+/// Typed fields of `EnvSettings`; anything else in `env` is an extra variable.
+const ENV_FIELDS: &[&str] = &[
+    "anthropic_base_url",
+    "anthropic_auth_token",
+    "api_timeout_ms",
+    "claude_code_disable_nonessential_traffic",
+    "anthropic_model",
+    "anthropic_small_fast_model",
+    "anthropic_default_sonnet_model",
+    "anthropic_default_opus_model",
+    "anthropic_default_haiku_model",
+];
+
+/// Top-level keys claude-provider itself understands; other keys are settings overlays.
+const PROVIDER_KEYS: &[&str] = &[
+    "schema_version",
+    "env",
+    "enabled_plugins",
+    "enabledPlugins",
+    "always_thinking_enabled",
+    "alwaysThinkingEnabled",
+    "launch_mode",
+    "env_merge",
+];
+
+/// Top-level Claude Code settings a provider can overlay. A key that is in neither list is most likely
+/// a typo, which claude would otherwise ignore without a word.
+const CLAUDE_SETTINGS_KEYS: &[&str] = &[
+    "$schema",
+    "apiKeyHelper",
+    "attribution",
+    "awsAuthRefresh",
+    "awsCredentialExport",
+    "cleanupPeriodDays",
+    "companyAnnouncements",
+    "disableAllHooks",
+    "disabledMcpjsonServers",
+    "enableAllProjectMcpServers",
+    "enabledMcpjsonServers",
+    "extraKnownMarketplaces",
+    "forceLoginMethod",
+    "forceLoginOrgUUID",
+    "hooks",
+    "includeCoAuthoredBy",
+    "model",
+    "otelHeadersHelper",
+    "outputStyle",
+    "permissions",
+    "sandbox",
+    "spinnerTipsEnabled",
+    "statusLine",
+    "subagentStatusLine",
+];
+
+/// `PROVIDER_MIGRATIONS[n]` upgrades a provider document from schema version n + 1 to n + 2.
+/// Version 1 is every file written before `schema_version` existed.
+const PROVIDER_MIGRATIONS: &[fn(&mut serde_json::Map<String, Value>)] = &[migrate_provider_v1];
+
+/// Version 1 files were sometimes hand-written with the real variable names (`ANTHROPIC_BASE_URL`)
+/// for the typed fields. Those ended up as extras, leaving the typed fields empty.
+fn migrate_provider_v1(doc: &mut serde_json::Map<String, Value>) {
+    let Some(env) = doc.get_mut("env").and_then(Value::as_object_mut) else {
+        return;
     };
+    for field in ENV_FIELDS {
+        if env.contains_key(*field) {
+            continue;
+        }
+        let Some(value) = env.remove(&field.to_ascii_uppercase()) else {
+            continue;
+        };
+        let value = match (*field, value) {
+            ("claude_code_disable_nonessential_traffic", Value::String(v)) => {
+                v.parse::<i32>().map(Value::from).unwrap_or(Value::String(v))
+            }
+            ("claude_code_disable_nonessential_traffic", v) => v,
+            (_, Value::String(v)) => Value::String(v),
+            (_, v) => Value::String(v.to_string()),
+        };
+        env.insert(field.to_string(), value);
+    }
+}
 
-    let settings = ClaudeSettings {
-        env,
-        enabled_plugins: Value::Object(serde_json::Map::new()),
-        always_thinking_enabled: None,
-        other: Value::Object(serde_json::Map::new()),
+/// Brings a provider document up to `PROVIDER_SCHEMA_VERSION` and returns the version it had.
+/// Documents from a newer version are refused: reading them would silently drop what they added.
+fn upgrade_provider_document(doc: &mut Value, source: &str) -> Result<u32> {
+    let Some(fields) = doc.as_object_mut() else {
+        return Err(anyhow!("{} must be a JSON object", source));
+    };
+    let version = match fields.get("schema_version") {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .filter(|v| *v >= 1)
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow!("{} has an invalid schema_version {}", source, v))?,
     };
+    if version > PROVIDER_SCHEMA_VERSION {
+        return Err(anyhow!(
+            "{} uses provider schema version {}, but this claude-provider only understands up to version {}. \
+             Upgrade claude-provider to use it.",
+            source,
+            version,
+            PROVIDER_SCHEMA_VERSION
+        ));
+    }
+    for migrate in &PROVIDER_MIGRATIONS[version as usize - 1..] {
+        migrate(fields);
+    }
+    fields.insert("schema_version".to_string(), Value::from(PROVIDER_SCHEMA_VERSION));
+    Ok(version)
+}
 
-    let providers_dir = ensure_providers_dir()?;
-    let provider_path = providers_dir.join(format!("{}.json", name));
+fn parse_provider_document(content: &str, source: &str) -> Result<ClaudeSettings> {
+    let mut doc: Value = serde_json::from_str(content).with_context(|| format!("Failed to parse {}", source))?;
+    upgrade_provider_document(&mut doc, source)?;
+    serde_json::from_value(doc).with_context(|| format!("Failed to parse {}", source))
+}
 
-    let content = serde_json::to_string_pretty(&settings)?;
-    fs::write(&provider_path, content)?;
+/// Validates a provider file's text. Env variables are usually upper case, so lower-case ones that
+/// look like our typed fields are most likely typos.
+fn provider_document_issues(content: &str) -> Vec<String> {
+    let (settings, mut issues) = check_provider_document(content);
+    issues.extend(settings.iter().flat_map(provider_settings_issues));
+    issues
+}
 
-    append_provider_function(&name)?;
+/// The structural part of `provider_document_issues`: everything but the values themselves, which
+/// callers that fill some in afterwards (like `add --from-json --token`) check once they're final.
+fn check_provider_document(content: &str) -> (Option<ClaudeSettings>, Vec<String>) {
+    let mut doc: Value = match serde_json::from_str(content) {
+        Ok(doc) => doc,
+        Err(e) => return (None, vec![format!("Invalid JSON: {}", e)]),
+    };
+    if let Err(e) = upgrade_provider_document(&mut doc, "The provider") {
+        return (None, vec![e.to_string()]);
+    }
+    let Some(fields) = doc.as_object() else {
+        return (None, vec!["A provider must be a JSON object".to_string()]);
+    };
+
+    // Compares spellings without case and underscores, so `launchMode` points to `launch_mode`.
+    let loose = |key: &str| key.replace('_', "").to_ascii_lowercase();
+    let mut issues = Vec::new();
+    for key in fields.keys() {
+        if PROVIDER_KEYS.contains(&key.as_str()) || CLAUDE_SETTINGS_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        match PROVIDER_KEYS.iter().chain(CLAUDE_SETTINGS_KEYS).find(|known| loose(known) == loose(key)) {
+            Some(known) => issues.push(format!("Unknown field '{}', did you mean '{}'?", key, known)),
+            None => issues.push(format!("Unknown field '{}'", key)),
+        }
+    }
+    if let Some(env) = fields.get("env").and_then(Value::as_object) {
+        for key in env.keys() {
+            let lower = key.to_ascii_lowercase();
+            let looks_typed = ["anthropic_", "api_", "claude_"].iter().any(|p| key.starts_with(p));
+            if looks_typed && !ENV_FIELDS.contains(&key.as_str()) && *key == lower {
+                issues.push(format!("Unknown env field '{}'", key));
+            }
+        }
+    }
+
+    match serde_json::from_value::<ClaudeSettings>(doc) {
+        Ok(settings) => (Some(settings), issues),
+        Err(e) => {
+            issues.push(format!("Invalid provider: {}", e));
+            (None, issues)
+        }
+    }
+}
+
+/// Builds a provider with the defaults the setup wizard has always used; models are left to the caller.
+fn new_provider_settings(base_url: String, api_key: String, extra: BTreeMap<String, String>) -> ClaudeSettings {
+    let env = EnvSettings {
+        anthropic_base_url: Some(base_url),
+        anthropic_auth_token: Some(api_key),
+        api_timeout_ms: Some("3000000".to_string()),
+        claude_code_disable_nonessential_traffic: Some(1),
+        extra,
+        ..Default::default()
+    };
+
+    ClaudeSettings {
+        schema_version: PROVIDER_SCHEMA_VERSION,
+        env,
+        enabled_plugins: Value::Object(serde_json::Map::new()),
+        always_thinking_enabled: None,
+        launch_mode: None,
+        env_merge: None,
+        other: Value::Object(serde_json::Map::new()),
+    }
+}
+
+/// Validates and writes a provider, then (re)generates its shell functions.
+fn save_provider(name: &str, settings: &ClaudeSettings, overwrite: bool) -> Result<PathBuf> {
+    validate_provider_name(name)?;
+    validate_provider_settings(settings)?;
+
+    let provider_path = provider_path(name)?;
+    if provider_path.exists() && !overwrite {
+        return Err(anyhow!("Provider '{}' already exists. Use --force to overwrite it.", name));
+    }
+
+    let content = serde_json::to_string_pretty(settings)?;
+    write_atomic(&provider_path, &content)?;
+    set_private_permissions(&provider_path)?;
+
+    append_provider_function(name)?;
+
+    Ok(provider_path)
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    let answer = prompt_input(prompt)?;
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+/// Asks the provider which models it serves so they can be picked instead of typed. Returns
+/// `None` (free-text entry) when stdin isn't a terminal or the provider can't list models.
+fn discover_models(base_url: &str, token: &str, extra: &BTreeMap<String, String>) -> Option<Vec<String>> {
+    if !io::stdin().is_terminal() || base_url.is_empty() || token.is_empty() {
+        return None;
+    }
+
+    let mut env = EnvSettings {
+        anthropic_base_url: Some(base_url.to_string()),
+        anthropic_auth_token: Some(token.to_string()),
+        extra: extra.clone(),
+        ..Default::default()
+    };
+    println!("  Fetching available models...");
+    let models = resolve_env_secrets(&mut env).and_then(|_| {
+        let agent = api_agent(&env)?;
+        list_models(&agent, base_url, env.anthropic_auth_token.as_deref().unwrap_or_default())
+    });
+    match models {
+        Ok(mut models) if !models.is_empty() => {
+            models.sort();
+            models.dedup();
+            Some(models)
+        }
+        Ok(_) => {
+            println!("  The provider returned no models; enter model IDs manually.");
+            None
+        }
+        Err(e) => {
+            println!("  Could not list models ({:#}); enter model IDs manually.", e);
+            None
+        }
+    }
+}
+
+/// Asks for one model slot. Enter keeps the current model or, when there is none, takes the model
+/// the slot follows; '-' clears it and '=' copies the followed model. Re-asks on unusable IDs.
+fn prompt_model_text(slot: ModelSlot, current: Option<&str>, inherited: Option<&(ModelSlot, String)>) -> Result<Option<String>> {
+    loop {
+        let prompt = match (current, inherited) {
+            (Some(model), _) => format!("  {} [{}]: ", slot.label(), model),
+            (None, Some((from, model))) => {
+                format!("  {} [same as {}: {}]: ", slot.label(), from.label().to_lowercase(), model)
+            }
+            (None, None) => format!("  {} (optional): ", slot.label()),
+        };
+        let answer = prompt_input(&prompt)?;
+        let model = match answer.as_str() {
+            "" => current.map(str::to_string).or_else(|| inherited.map(|(_, m)| m.clone())),
+            "-" => None,
+            "=" => inherited.map(|(_, m)| m.clone()),
+            _ => Some(answer),
+        };
+        match model.as_deref().and_then(|m| slot.issue(m)) {
+            Some(issue) => println!("  ✗ {}", issue),
+            None => return Ok(model),
+        }
+    }
+}
+
+/// Like `prompt_model_text`, but offers an arrow-key picker when the provider's models are known.
+fn prompt_model(
+    slot: ModelSlot,
+    current: Option<&str>,
+    inherited: Option<&(ModelSlot, String)>,
+    models: Option<&[String]>,
+) -> Result<Option<String>> {
+    let Some(models) = models else {
+        return prompt_model_text(slot, current, inherited);
+    };
+
+    let keep = current.map(|model| format!("Keep current: {}", model));
+    let same = inherited
+        .filter(|(_, model)| Some(model.as_str()) != current)
+        .map(|(from, model)| format!("Same as {}: {}", from.label().to_lowercase(), model));
+    let mut options: Vec<&str> = Vec::new();
+    options.extend(keep.as_deref());
+    options.extend(same.as_deref());
+    let first_model = options.len();
+    options.extend(models.iter().map(String::as_str));
+    options.push("Enter a model ID manually");
+    options.push(if current.is_some() { "Clear" } else { "Leave unset" });
+
+    enable_raw_mode()?;
+    let idx = draw_menu_with_arrows(&options, &format!("{} ({})", slot.label(), slot.env_var()));
+    disable_raw_mode().ok();
+    clear_screen();
+
+    let model = match idx {
+        _ if idx >= options.len() => current.map(str::to_string),
+        0 if keep.is_some() => current.map(str::to_string),
+        _ if same.is_some() && idx + 1 == first_model => inherited.map(|(_, m)| m.clone()),
+        _ if idx + 2 == options.len() => return prompt_model_text(slot, current, inherited),
+        _ if idx + 1 == options.len() => None,
+        _ => Some(options[idx].to_string()),
+    };
+    println!("  {}: {}", slot.label(), model.as_deref().unwrap_or("(unset)"));
+    Ok(model)
+}
+
+/// Prompts for every model slot in order, so later slots can default to earlier answers.
+fn prompt_models(env: &mut EnvSettings, models: Option<&[String]>) -> Result<()> {
+    for slot in ModelSlot::ALL {
+        let inherited = slot.inherited(env);
+        let current = slot.get(env).map(str::to_string);
+        *slot.get_mut(env) = prompt_model(slot, current.as_deref(), inherited.as_ref(), models)?;
+    }
+    Ok(())
+}
+
+fn print_model_summary(env: &EnvSettings, models: Option<&[String]>) {
+    println!();
+    println!("  {:<18} {:<32} Model", "Slot", "Variable");
+    let mut warnings = Vec::new();
+    for slot in ModelSlot::ALL {
+        let model = slot.get(env);
+        let note = match slot.inherited(env) {
+            Some((from, inherited)) if model == Some(inherited.as_str()) => {
+                format!("  (same as {})", from.label().to_lowercase())
+            }
+            _ => String::new(),
+        };
+        println!("  {:<18} {:<32} {}{}", slot.label(), slot.env_var(), model.unwrap_or("-"), note);
+
+        let Some(model) = model else { continue };
+        warnings.extend(slot.warning(model));
+        if let Some(models) = models
+            && !models.iter().any(|m| m == model)
+        {
+            warnings.push(format!("{} '{}' is not listed by the provider", slot.label(), model));
+        }
+    }
+    if !warnings.is_empty() {
+        println!();
+        for warning in warnings {
+            println!("  ⚠ {}", warning);
+        }
+    }
+    println!();
+}
+
+/// Final check after the summary; scripted input (no terminal) always saves.
+fn confirm_save() -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    let answer = prompt_input("  Save provider? [Y/n] ")?;
+    Ok(!(answer.eq_ignore_ascii_case("n") || answer.eq_ignore_ascii_case("no")))
+}
+
+fn setup_provider_interactive() -> Result<()> {
+    println!();
+    println!("  ▸ Configure a new Claude Code provider");
+    println!();
+
+    let name = prompt_input("  Enter provider name (e.g., minimax, zai): ")?;
+    validate_provider_name(&name)?;
+    let overwrite = provider_path(&name)?.exists();
+    if overwrite && !confirm(&format!("  Provider '{}' already exists. Overwrite it? [y/N] ", name))? {
+        return Ok(());
+    }
+
+    let base_url = prompt_input("  Enter API base URL: ")?;
+    if base_url.is_empty() {
+        return Err(anyhow!("Base URL cannot be empty"));
+    }
+
+    let api_key = prompt_secret("  Enter API key: ")?;
+    if api_key.is_empty() {
+        return Err(anyhow!("API key cannot be empty"));
+    }
+
+    // Asked before the models so a proxy set here is used to list them.
+    let mut extra = BTreeMap::new();
+    prompt_extra_env(&mut extra)?;
+
+    let mut settings = new_provider_settings(base_url, api_key, extra);
+    let env = &mut settings.env;
+    let models = discover_models(
+        env.anthropic_base_url.as_deref().unwrap_or_default(),
+        env.anthropic_auth_token.as_deref().unwrap_or_default(),
+        &env.extra,
+    );
+    prompt_models(env, models.as_deref())?;
+
+    print_model_summary(&settings.env, models.as_deref());
+    if !confirm_save()? {
+        println!("  Discarded.");
+        return Ok(());
+    }
+    let provider_path = save_provider(&name, &settings, overwrite)?;
 
     println!();
     println!("  ✓ Provider '{}' saved to {}", name, provider_path.display());
-    println!("  ✓ Shell functions created for bash and zsh: '{}'", name);
+    if ToolConfig::load()?.shell_function_files.unwrap_or(true) {
+        let shells: Vec<&str> = integrated_shells().into_iter().map(|shell| shell.name()).collect();
+        println!("  ✓ Shell functions created for {}: '{}'", shells.join(", "), name);
+    } else {
+        println!("  ✓ '{}' is available in new shells through 'claude-provider init'", name);
+    }
     println!();
     print!("  Press Enter to continue...");
     io::stdout().flush().unwrap();
@@ -437,113 +1393,2714 @@ fn setup_provider_interactive() -> Result<()> {
     Ok(())
 }
 
-fn remove_provider_interactive() -> Result<()> {
-    let providers = list_providers()?;
+/// Prompts with the current value shown; Enter keeps it and '-' clears it.
+fn prompt_with_current(label: &str, current: Option<&str>) -> Result<Option<String>> {
+    let answer = match current {
+        Some(value) => prompt_input(&format!("  {} [{}]: ", label, value))?,
+        None => prompt_input(&format!("  {}: ", label))?,
+    };
+    Ok(match answer.as_str() {
+        "" => current.map(str::to_string),
+        "-" => None,
+        _ => Some(answer),
+    })
+}
 
-    if providers.is_empty() {
-        clear_screen();
-        let mut stdout = io::stdout();
-        execute!(stdout, MoveTo(0, 0)).unwrap();
-        print!("\n  No providers configured.\n");
-        wait_for_key();
-        return Ok(());
+fn edit_provider_form(settings: &mut ClaudeSettings) -> Result<()> {
+    println!("  Press Enter to keep a value, or '-' to clear it. For a model, '=' copies the model it");
+    println!("  defaults to (sonnet and opus follow the default model, small/fast follows haiku).");
+    println!();
+
+    let env = &mut settings.env;
+    env.anthropic_base_url = prompt_with_current("API base URL", env.anthropic_base_url.as_deref())?;
+
+    let change_token = match env.anthropic_auth_token.as_deref() {
+        Some(token) if is_secret_reference(token) => confirm(&format!("  Change API key? Current: {} [y/N] ", token))?,
+        Some(token) => confirm(&format!("  Change API key? Current: {} [y/N] ", secret_hint(token)))?,
+        None => true,
+    };
+    if change_token {
+        let api_key = prompt_secret("  Enter API key: ")?;
+        if !api_key.is_empty() {
+            env.anthropic_auth_token = Some(api_key);
+        }
     }
 
-    let options: Vec<&str> = providers.iter().map(|s| s.as_str()).collect();
-    let idx = draw_menu_with_arrows(&options, "Remove Provider");
+    let models = discover_models(
+        env.anthropic_base_url.as_deref().unwrap_or_default(),
+        env.anthropic_auth_token.as_deref().unwrap_or_default(),
+        &env.extra,
+    );
+    prompt_models(env, models.as_deref())?;
+    env.api_timeout_ms = prompt_with_current("API timeout (ms)", env.api_timeout_ms.as_deref())?;
 
-    if idx >= providers.len() {
+    prompt_extra_env(&mut env.extra)?;
+
+    let launch_mode = prompt_with_current(
+        "Launch mode (env/settings, '-' for the global default)",
+        settings.launch_mode.as_ref().map(LaunchMode::as_str),
+    )?;
+    settings.launch_mode = launch_mode
+        .map(|value| LaunchMode::from_str(&value, true).map_err(|_| anyhow!("Invalid launch mode '{}'", value)))
+        .transpose()?;
+
+    print_model_summary(&settings.env, models.as_deref());
+    Ok(())
+}
+
+fn edit_provider_interactive(name: &str) -> Result<()> {
+    let mut settings = load_provider(name)?;
+
+    println!();
+    println!("  ▸ Edit provider '{}'", name);
+    println!();
+
+    edit_provider_form(&mut settings)?;
+    if !confirm_save()? {
+        println!("  Discarded.");
         return Ok(());
     }
+    let provider_path = save_provider(name, &settings, true)?;
 
-    let provider_name = &providers[idx];
-    let providers_dir = ensure_providers_dir()?;
-    let provider_path = providers_dir.join(format!("{}.json", provider_name));
+    println!();
+    println!("  ✓ Provider '{}' saved to {}", name, provider_path.display());
+    Ok(())
+}
 
-    fs::remove_file(&provider_path)?;
+/// Marks the error annotations we put at the top of the file, so they can be stripped again.
+const EDITOR_ANNOTATION: &str = "// claude-provider:";
 
-    remove_provider_function(provider_name)?;
+fn strip_editor_annotations(content: &str) -> String {
+    content
+        .lines()
+        .skip_while(|line| line.starts_with(EDITOR_ANNOTATION))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-    clear_screen();
-    let mut stdout = io::stdout();
-    execute!(stdout, MoveTo(0, 0)).unwrap();
-    print!("\n  Provider '{}' removed.\n", provider_name);
-    wait_for_key();
+fn run_editor(path: &Path) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
 
+    // Through the shell so editors configured with arguments (e.g. "code --wait") work.
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to start editor '{}'", editor))?;
+    if !status.success() {
+        return Err(anyhow!("Editor '{}' exited with {}", editor, status));
+    }
     Ok(())
 }
 
-fn build_env_object(env: &EnvSettings) -> serde_json::Map<String, Value> {
-    let mut obj = serde_json::Map::new();
+/// A directory only we can enter for files holding secrets. `mkdir` fails instead of
+/// following anything another user planted at the path, so a taken name just moves on to the next.
+struct PrivateTempDir(PathBuf);
 
-    if let Some(v) = &env.anthropic_base_url {
-        obj.insert("ANTHROPIC_BASE_URL".to_string(), Value::String(v.clone()));
-    }
-    if let Some(v) = &env.anthropic_auth_token {
-        obj.insert("ANTHROPIC_AUTH_TOKEN".to_string(), Value::String(v.clone()));
+impl PrivateTempDir {
+    fn new() -> Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+        for attempt in 0..100 {
+            let dir = std::env::temp_dir().join(format!("claude-provider-{}-{}", std::process::id(), attempt));
+            match fs::DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => return Ok(Self(dir)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to create {}", dir.display())),
+            }
+        }
+        Err(anyhow!("Failed to create a private directory in {}", std::env::temp_dir().display()))
     }
-    if let Some(v) = &env.api_timeout_ms {
-        obj.insert("API_TIMEOUT_MS".to_string(), Value::String(v.clone()));
+}
+
+/// Removes the directory on every way out, so a plaintext copy never outlives an error.
+impl Drop for PrivateTempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
     }
-    if let Some(v) = env.claude_code_disable_nonessential_traffic {
-        obj.insert("CLAUDE_CODE_DISABLE_NONESSENTIAL_TRAFFIC".to_string(), Value::Number(v.into()));
+}
+
+/// `edit --editor`: edit a private temporary copy and only write it back once it validates.
+fn edit_provider_in_editor(name: &str) -> Result<()> {
+    let provider_path = provider_path(name)?;
+    if !provider_path.exists() {
+        return Err(anyhow!("Provider '{}' not found. Run 'claude-provider setup' first.", name));
     }
-    if let Some(v) = &env.anthropic_model {
-        obj.insert("ANTHROPIC_MODEL".to_string(), Value::String(v.clone()));
+    let original = fs::read_to_string(&provider_path)?;
+
+    let tmp_dir = PrivateTempDir::new()?;
+    let tmp_path = tmp_dir.0.join(format!("{}.json", name));
+    write_atomic(&tmp_path, &original)?;
+
+    let result = loop {
+        if let Err(e) = run_editor(&tmp_path) {
+            break Err(e);
+        }
+
+        let edited = strip_editor_annotations(&fs::read_to_string(&tmp_path)?);
+        let issues = provider_document_issues(&edited);
+        if issues.is_empty() {
+            break Ok(Some(edited));
+        }
+
+        eprintln!();
+        for issue in &issues {
+            eprintln!("  ✗ {}", issue);
+        }
+        if !confirm("  Edit again? [y/N] ")? {
+            break Ok(None);
+        }
+
+        let annotations: String = issues.iter().map(|issue| format!("{} {}\n", EDITOR_ANNOTATION, issue)).collect();
+        write_atomic(&tmp_path, &format!("{}{}", annotations, edited))?;
+    };
+    drop(tmp_dir);
+
+    match result? {
+        None => println!("  Changes discarded."),
+        Some(edited) if edited.trim() == original.trim() => println!("  No changes."),
+        Some(edited) => {
+            write_atomic(&provider_path, &edited)?;
+            append_provider_function(name)?;
+            println!("  ✓ Provider '{}' saved to {}", name, provider_path.display());
+        }
     }
-    if let Some(v) = &env.anthropic_small_fast_model {
-        obj.insert("ANTHROPIC_SMALL_FAST_MODEL".to_string(), Value::String(v.clone()));
+    Ok(())
+}
+
+fn pick_and_edit_provider_interactive() -> Result<()> {
+    let providers = list_providers()?;
+    if providers.is_empty() {
+        print!("\n  No providers configured.\n");
+        wait_for_key();
+        return Ok(());
     }
-    if let Some(v) = &env.anthropic_default_sonnet_model {
-        obj.insert("ANTHROPIC_DEFAULT_SONNET_MODEL".to_string(), Value::String(v.clone()));
+
+    enable_raw_mode()?;
+    let options: Vec<&str> = providers.iter().map(|s| s.as_str()).collect();
+    let idx = draw_menu_with_arrows(&options, "Edit Provider");
+    disable_raw_mode().ok();
+    clear_screen();
+
+    if let Some(name) = providers.get(idx) {
+        edit_provider_interactive(name)?;
+        wait_for_key();
     }
-    if let Some(v) = &env.anthropic_default_opus_model {
-        obj.insert("ANTHROPIC_DEFAULT_OPUS_MODEL".to_string(), Value::String(v.clone()));
+    Ok(())
+}
+
+/// Fields accepted by `set`/`unset`, besides `env.<NAME>` and `settings.<key>`.
+const PROVIDER_FIELDS: &[&str] = &[
+    "base-url",
+    "token",
+    "model",
+    "sonnet-model",
+    "opus-model",
+    "haiku-model",
+    "small-fast-model",
+    "timeout-ms",
+    "disable-nonessential-traffic",
+    "thinking",
+    "launch-mode",
+    "env-merge",
+];
+
+/// Sets (or with `None`, clears) one field of a provider.
+fn set_provider_field(settings: &mut ClaudeSettings, field: &str, value: Option<String>) -> Result<()> {
+    if let Some(key) = field.strip_prefix("env.") {
+        let (key, _) = parse_env_assignment(&format!("{}=", key))?;
+        match value {
+            Some(value) => settings.env.extra.insert(key, value),
+            None => settings.env.extra.remove(&key),
+        };
+        return Ok(());
     }
-    if let Some(v) = &env.anthropic_default_haiku_model {
-        obj.insert("ANTHROPIC_DEFAULT_HAIKU_MODEL".to_string(), Value::String(v.clone()));
+
+    if let Some(key) = field.strip_prefix("settings.") {
+        let other = settings.other.as_object_mut().ok_or_else(|| anyhow!("Provider file is malformed"))?;
+        match value {
+            // Accept JSON so objects, arrays and booleans can be set; anything else is a string.
+            Some(_) if !CLAUDE_SETTINGS_KEYS.contains(&key) => {
+                return Err(anyhow!("'{}' is not a Claude Code setting claude-provider knows", key));
+            }
+            Some(value) => {
+                let parsed = serde_json::from_str(&value).unwrap_or(Value::String(value));
+                other.insert(key.to_string(), parsed)
+            }
+            None => other.remove(key),
+        };
+        return Ok(());
     }
 
-    obj
+    let env = &mut settings.env;
+    match field {
+        "base-url" => env.anthropic_base_url = value,
+        "token" => env.anthropic_auth_token = value,
+        "model" => env.anthropic_model = value,
+        "sonnet-model" => env.anthropic_default_sonnet_model = value,
+        "opus-model" => env.anthropic_default_opus_model = value,
+        "haiku-model" => env.anthropic_default_haiku_model = value,
+        "small-fast-model" => env.anthropic_small_fast_model = value,
+        "timeout-ms" => {
+            if let Some(v) = &value {
+                v.parse::<u64>().map_err(|_| anyhow!("timeout-ms must be a number, got '{}'", v))?;
+            }
+            env.api_timeout_ms = value;
+        }
+        "disable-nonessential-traffic" => {
+            env.claude_code_disable_nonessential_traffic = value
+                .map(|v| v.parse::<i32>().map_err(|_| anyhow!("disable-nonessential-traffic must be 0 or 1, got '{}'", v)))
+                .transpose()?;
+        }
+        "thinking" => {
+            settings.always_thinking_enabled = value
+                .map(|v| v.parse::<bool>().map_err(|_| anyhow!("thinking must be true or false, got '{}'", v)))
+                .transpose()?;
+        }
+        "launch-mode" => {
+            settings.launch_mode = value
+                .map(|v| LaunchMode::from_str(&v, true).map_err(|_| anyhow!("Invalid launch mode '{}'", v)))
+                .transpose()?;
+        }
+        "env-merge" => {
+            settings.env_merge = value
+                .map(|v| EnvMergeStrategy::from_str(&v, true).map_err(|_| anyhow!("Invalid env merge strategy '{}'", v)))
+                .transpose()?;
+        }
+        _ => {
+            return Err(anyhow!(
+                "Unknown field '{}'. Use one of {}, env.<NAME> or settings.<key>",
+                field,
+                PROVIDER_FIELDS.join(", ")
+            ));
+        }
+    }
+    Ok(())
 }
 
-fn run_with_provider(provider_name: &str, args: &[String]) -> Result<()> {
-    let providers_dir = ensure_providers_dir()?;
-    let provider_path = providers_dir.join(format!("{}.json", provider_name));
+fn set_field_command(name: &str, field: &str, value: Option<String>) -> Result<()> {
+    let mut settings = load_provider(name)?;
 
-    if !provider_path.exists() {
-        return Err(anyhow!("Provider '{}' not found. Run 'claude-provider setup' first.", provider_name));
+    // `set <name> token -` reads the token from stdin to keep it out of shell history.
+    let value = match value {
+        Some(v) if v == "-" && field == "token" => Some(read_stdin_to_string()?.trim().to_string()),
+        other => other,
+    };
+    let cleared = value.is_none();
+
+    set_provider_field(&mut settings, field, value)?;
+    save_provider(name, &settings, true)?;
+
+    if cleared {
+        println!("  ✓ Cleared {} on '{}'", field, name);
+    } else {
+        println!("  ✓ Set {} on '{}'", field, name);
     }
+    Ok(())
+}
 
-    let provider_content = fs::read_to_string(&provider_path)?;
-    let provider_settings: ClaudeSettings = serde_json::from_str(&provider_content)?;
+/// Options for `add`, the scriptable counterpart of `setup`.
+struct AddOptions {
+    name: Option<String>,
+    base_url: Option<String>,
+    token: Option<String>,
+    token_stdin: bool,
+    model: Option<String>,
+    sonnet_model: Option<String>,
+    opus_model: Option<String>,
+    haiku_model: Option<String>,
+    small_fast_model: Option<String>,
+    env: Vec<String>,
+    launch_mode: Option<LaunchMode>,
+    env_merge: Option<EnvMergeStrategy>,
+    from_json: Option<String>,
+    force: bool,
+}
 
-    let config_dir = get_config_dir()?;
-    let settings_path = config_dir.join(SETTINGS_FILE);
-    let settings_content = fs::read_to_string(&settings_path)?;
-    let mut settings: Value = serde_json::from_str(&settings_content)?;
+fn read_stdin_to_string() -> Result<String> {
+    let mut content = String::new();
+    io::Read::read_to_string(&mut io::stdin(), &mut content).context("Failed to read stdin")?;
+    Ok(content)
+}
 
-    let env_obj = build_env_object(&provider_settings.env);
-    settings.as_object_mut().expect("settings should be an object").insert("env".to_string(), Value::Object(env_obj));
+fn add_provider_command(opts: AddOptions) -> Result<()> {
+    if opts.token_stdin && opts.from_json.as_deref() == Some("-") {
+        return Err(anyhow!("--token-stdin and --from-json - can't both read stdin"));
+    }
 
-    let modified_content = serde_json::to_string_pretty(&settings)?;
-    fs::write(&settings_path, modified_content)?;
+    let (name, mut settings) = match &opts.from_json {
+        Some(source) => {
+            let content = if source == "-" {
+                read_stdin_to_string()?
+            } else {
+                fs::read_to_string(source).with_context(|| format!("Failed to read {}", source))?
+            };
+            let mut doc: Value =
+                serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", source))?;
+            // A document may carry its own name; it must not leak into the settings overlay.
+            let doc_name = doc
+                .as_object_mut()
+                .and_then(|fields| fields.remove("name"))
+                .and_then(|v| v.as_str().map(str::to_string));
+            let (settings, issues) = check_provider_document(&doc.to_string());
+            let Some(settings) = settings.filter(|_| issues.is_empty()) else {
+                return Err(anyhow!("Invalid provider document from {}:\n  {}", source, issues.join("\n  ")));
+            };
+            let name = opts
+                .name
+                .clone()
+                .or(doc_name)
+                .ok_or_else(|| anyhow!("No provider name given and the document has no \"name\" field"))?;
+            (name, settings)
+        }
+        None => {
+            let name = opts.name.clone().ok_or_else(|| anyhow!("A provider name is required"))?;
+            let base_url = opts.base_url.clone().ok_or_else(|| anyhow!("--base-url is required"))?;
+            let api_key = match &opts.token {
+                Some(token) => token.clone(),
+                None if opts.token_stdin => read_stdin_to_string()?.trim().to_string(),
+                None => return Err(anyhow!("--token or --token-stdin is required")),
+            };
 
-    disable_raw_mode().ok();
+            (name, new_provider_settings(base_url, api_key, BTreeMap::new()))
+        }
+    };
 
-    let status = Command::new("claude")
-        .args(args)
-        .status()
-        .context("Failed to execute claude")?;
+    if opts.from_json.is_some() {
+        if let Some(base_url) = opts.base_url {
+            settings.env.anthropic_base_url = Some(base_url);
+        }
+        if let Some(token) = opts.token {
+            settings.env.anthropic_auth_token = Some(token);
+        } else if opts.token_stdin {
+            settings.env.anthropic_auth_token = Some(read_stdin_to_string()?.trim().to_string());
+        }
+    }
+
+    // Slots not given follow the one they default to, as in setup, but only when that one was given
+    // here too, so a document's own models aren't overwritten.
+    let models = [opts.model, opts.sonnet_model, opts.opus_model, opts.haiku_model, opts.small_fast_model];
+    let mut given = Vec::new();
+    for (slot, model) in ModelSlot::ALL.into_iter().zip(models) {
+        if model.is_some() {
+            *slot.get_mut(&mut settings.env) = model;
+            given.push(slot);
+        }
+    }
+    for slot in ModelSlot::ALL {
+        if !given.contains(&slot) && slot.follows().iter().any(|s| given.contains(s)) {
+            *slot.get_mut(&mut settings.env) = slot.inherited(&settings.env).map(|(_, m)| m);
+        }
+    }
+    for assignment in &opts.env {
+        let (key, value) = parse_env_assignment(assignment)?;
+        settings.env.extra.insert(key, value);
+    }
+    if opts.launch_mode.is_some() {
+        settings.launch_mode = opts.launch_mode;
+    }
+    if opts.env_merge.is_some() {
+        settings.env_merge = opts.env_merge;
+    }
+
+    let issues = provider_settings_issues(&settings);
+    if let Some(source) = &opts.from_json
+        && !issues.is_empty()
+    {
+        return Err(anyhow!("Invalid provider document from {}:\n  {}", source, issues.join("\n  ")));
+    }
+    let provider_path = save_provider(&name, &settings, opts.force)?;
+
+    println!("  ✓ Provider '{}' saved to {}", name, provider_path.display());
+    Ok(())
+}
+
+fn delete_provider(name: &str) -> Result<()> {
+    let path = provider_path(name)?;
+    fs::remove_file(&path)?;
+    for backup in provider_backups(path.parent().unwrap_or(Path::new(".")), Some(name)) {
+        fs::remove_file(backup)?;
+    }
+    remove_provider_function(name)?;
+    Ok(())
+}
+
+fn remove_provider_interactive() -> Result<()> {
+    let providers = list_providers()?;
+
+    if providers.is_empty() {
+        clear_screen();
+        let mut stdout = io::stdout();
+        execute!(stdout, MoveTo(0, 0)).unwrap();
+        print!("\n  No providers configured.\n");
+        wait_for_key();
+        return Ok(());
+    }
+
+    let options: Vec<&str> = providers.iter().map(|s| s.as_str()).collect();
+    let idx = draw_menu_with_arrows(&options, "Remove Provider");
+
+    if idx >= providers.len() {
+        return Ok(());
+    }
+
+    let provider_name = &providers[idx];
+    delete_provider(provider_name)?;
+
+    clear_screen();
+    let mut stdout = io::stdout();
+    execute!(stdout, MoveTo(0, 0)).unwrap();
+    print!("\n  Provider '{}' removed.\n", provider_name);
+    wait_for_key();
+
+    Ok(())
+}
+
+/// Writes `src` under a new name with its shell functions; the source is left in place.
+fn duplicate_provider(src: &str, dst: &str, force: bool) -> Result<PathBuf> {
+    validate_provider_name(dst)?;
+    if src == dst {
+        return Err(anyhow!("Source and destination are both '{}'", src));
+    }
+
+    let src_path = provider_path(src)?;
+    if !src_path.exists() {
+        return Err(anyhow!("Provider '{}' not found. Run 'claude-provider list' to see configured providers.", src));
+    }
+    let dst_path = provider_path(dst)?;
+    if dst_path.exists() && !force {
+        return Err(anyhow!("Provider '{}' already exists. Use --force to overwrite it.", dst));
+    }
+
+    // Copy the text rather than re-serializing so formatting and key order survive.
+    let content = fs::read_to_string(&src_path)?;
+    write_atomic(&dst_path, &content)?;
+    set_private_permissions(&dst_path)?;
+    append_provider_function(dst)?;
+
+    Ok(dst_path)
+}
+
+fn copy_provider_command(src: &str, dst: &str, force: bool) -> Result<()> {
+    let dst_path = duplicate_provider(src, dst, force)?;
+    println!("  ✓ Copied '{}' to '{}' ({})", src, dst, dst_path.display());
+    Ok(())
+}
+
+fn rename_provider_command(old: &str, new: &str, force: bool) -> Result<()> {
+    let new_path = duplicate_provider(old, new, force)?;
+    delete_provider(old)?;
+
+    // A running settings-mode session keeps working; only its label needs to follow the rename.
+    if let Some(mut journal) = SwapJournal::load()?
+        && journal.provider == old
+    {
+        journal.provider = new.to_string();
+        journal.save()?;
+    }
+
+    println!("  ✓ Renamed '{}' to '{}' ({})", old, new, new_path.display());
+    Ok(())
+}
+
+/// Expands provider names and glob patterns (`staging-*`) against the configured providers.
+fn resolve_provider_patterns(patterns: &[String]) -> Result<Vec<String>> {
+    let providers = list_providers()?;
+    let mut resolved: Vec<String> = Vec::new();
+    let mut unknown = Vec::new();
+
+    for pattern in patterns {
+        let matches: Vec<&String> = if pattern.contains(['*', '?', '[']) {
+            let glob = glob::Pattern::new(pattern).with_context(|| format!("Invalid pattern '{}'", pattern))?;
+            providers.iter().filter(|p| glob.matches(p)).collect()
+        } else {
+            providers.iter().filter(|p| *p == pattern).collect()
+        };
+
+        if matches.is_empty() {
+            unknown.push(pattern.as_str());
+        }
+        for name in matches {
+            if !resolved.contains(name) {
+                resolved.push(name.clone());
+            }
+        }
+    }
+
+    if !unknown.is_empty() {
+        return Err(anyhow!(
+            "No provider matches {}. Run 'claude-provider list' to see configured providers.",
+            unknown.iter().map(|u| format!("'{}'", u)).collect::<Vec<_>>().join(", ")
+        ));
+    }
+
+    Ok(resolved)
+}
+
+fn remove_providers_command(patterns: &[String], yes: bool) -> Result<()> {
+    let names = resolve_provider_patterns(patterns)?;
+
+    // A script would read EOF as "no" and carry on as if the providers were gone.
+    if !yes && !io::stdin().is_terminal() {
+        return Err(anyhow!("Not removing {} without a terminal to confirm on. Pass --yes.", names.join(", ")));
+    }
+    if !yes {
+        println!();
+        println!("  The following providers will be removed:");
+        for name in &names {
+            println!("    {}", name);
+        }
+        println!();
+        if !confirm("  Continue? [y/N] ")? {
+            println!("  Aborted.");
+            return Ok(());
+        }
+    }
+
+    for name in &names {
+        delete_provider(name)?;
+        println!("  ✓ Provider '{}' removed", name);
+    }
+
+    Ok(())
+}
+
+/// Env variables besides the auth token that `export` treats as secrets, by name.
+fn is_secret_env_key(key: &str) -> bool {
+    let key = key.to_ascii_uppercase();
+    ["TOKEN", "KEY", "SECRET", "PASSWORD"].iter().any(|s| key.contains(s))
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProviderBundle {
+    format: String,
+    version: u32,
+    providers: BTreeMap<String, Value>,
+}
+
+fn export_command(patterns: &[String], output: Option<PathBuf>, tokens: TokenExport) -> Result<()> {
+    let names = if patterns.is_empty() { list_providers()? } else { resolve_provider_patterns(patterns)? };
+    if names.is_empty() {
+        return Err(anyhow!("No providers to export"));
+    }
+
+    let mut providers = BTreeMap::new();
+    let mut plaintext = false;
+    for name in &names {
+        let mut settings = load_provider(name)?;
+        let token_placeholder = format!("env:{}_AUTH_TOKEN", name.to_ascii_uppercase().replace('-', "_"));
+        let env = &mut settings.env;
+        let secrets = env
+            .anthropic_auth_token
+            .iter_mut()
+            .map(|token| (token_placeholder.clone(), token))
+            .chain(env.extra.iter_mut().filter(|(k, _)| is_secret_env_key(k)).map(|(k, v)| (format!("env:{}", k), v)));
+        for (placeholder, secret) in secrets {
+            if secret.is_empty() || is_secret_reference(secret) {
+                continue;
+            }
+            match tokens {
+                TokenExport::Keep => plaintext = true,
+                TokenExport::Redact => secret.clear(),
+                TokenExport::Env => *secret = placeholder,
+            }
+        }
+        providers.insert(name.clone(), serde_json::to_value(&settings)?);
+    }
+
+    let bundle = ProviderBundle { format: BUNDLE_FORMAT.to_string(), version: BUNDLE_VERSION, providers };
+    let content = serde_json::to_string_pretty(&bundle)?;
+    match output {
+        Some(path) => {
+            write_atomic(&path, &content)?;
+            eprintln!("  ✓ Exported {} provider(s) to {}", names.len(), path.display());
+            if plaintext {
+                eprintln!("  ! The bundle contains plaintext tokens; use --tokens redact or --tokens env to share it.");
+            }
+        }
+        None => println!("{}", content),
+    }
+    Ok(())
+}
+
+/// Parses a bundle, refusing other files and bundles written by a newer version.
+fn parse_bundle(content: &str, source: &str) -> Result<ProviderBundle> {
+    let value: Value = serde_json::from_str(content).with_context(|| format!("{} is not valid JSON", source))?;
+    if value.get("format").and_then(Value::as_str) != Some(BUNDLE_FORMAT) {
+        return Err(anyhow!("{} is not a claude-provider bundle", source));
+    }
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or_default();
+    if version > BUNDLE_VERSION as u64 {
+        return Err(anyhow!(
+            "{} is a version {} bundle, but this claude-provider only reads up to version {}. Please upgrade.",
+            source,
+            version,
+            BUNDLE_VERSION
+        ));
+    }
+    serde_json::from_value(value).with_context(|| format!("{} is a malformed bundle", source))
+}
+
+fn free_provider_name(name: &str) -> Result<String> {
+    let mut suffix = 2;
+    loop {
+        let candidate = format!("{}-{}", name, suffix);
+        if !provider_path(&candidate)?.exists() {
+            return Ok(candidate);
+        }
+        suffix += 1;
+    }
+}
+
+/// Decides the name to import `name` under, or `None` to skip it. Asks when no policy was given.
+fn resolve_import_name(name: &str, on_conflict: Option<ImportConflict>) -> Result<Option<String>> {
+    if !provider_path(name)?.exists() {
+        return Ok(Some(name.to_string()));
+    }
+    let policy = match on_conflict {
+        Some(policy) => policy,
+        None => loop {
+            let answer = prompt_input(&format!("  '{}' already exists. [s]kip, [o]verwrite or [r]ename? ", name))?;
+            match answer.to_ascii_lowercase().as_str() {
+                "s" | "skip" => break ImportConflict::Skip,
+                "o" | "overwrite" => break ImportConflict::Overwrite,
+                "r" | "rename" => break ImportConflict::Rename,
+                _ => {}
+            }
+        },
+    };
+    Ok(match policy {
+        ImportConflict::Skip => None,
+        ImportConflict::Overwrite => Some(name.to_string()),
+        ImportConflict::Rename if on_conflict.is_none() => loop {
+            let suggested = free_provider_name(name)?;
+            let answer = prompt_input(&format!("  New name [{}]: ", suggested))?;
+            let new_name = if answer.is_empty() { suggested } else { answer };
+            validate_provider_name(&new_name)?;
+            if !provider_path(&new_name)?.exists() {
+                break Some(new_name);
+            }
+            println!("  '{}' already exists too.", new_name);
+        },
+        ImportConflict::Rename => Some(free_provider_name(name)?),
+    })
+}
+
+fn import_command(source: &str, on_conflict: Option<ImportConflict>) -> Result<()> {
+    let content = if source == "-" {
+        read_stdin_to_string()?
+    } else {
+        fs::read_to_string(source).with_context(|| format!("Failed to read {}", source))?
+    };
+    let source = if source == "-" { "stdin" } else { source };
+    let bundle = parse_bundle(&content, source)?;
+
+    // Without a terminal to ask on, make the caller choose up front instead of half-importing.
+    let interactive = source != "stdin" && io::stdin().is_terminal();
+    if on_conflict.is_none() && !interactive {
+        let conflicts: Vec<&str> = bundle
+            .providers
+            .keys()
+            .filter(|name| provider_path(name).is_ok_and(|p| p.exists()))
+            .map(String::as_str)
+            .collect();
+        if !conflicts.is_empty() {
+            return Err(anyhow!(
+                "Already configured: {}. Pass --on-conflict skip, overwrite or rename.",
+                conflicts.join(", ")
+            ));
+        }
+    }
+
+    // Check the whole bundle before writing anything. Redacted tokens are asked for below.
+    let mut providers = Vec::new();
+    for (name, document) in &bundle.providers {
+        validate_provider_name(name)?;
+        let mut document = document.clone();
+        upgrade_provider_document(&mut document, &format!("Provider '{}' in {}", name, source))?;
+        let issues: Vec<String> = provider_document_issues(&document.to_string())
+            .into_iter()
+            .filter(|issue| issue != "API key cannot be empty")
+            .collect();
+        if !issues.is_empty() {
+            return Err(anyhow!("Provider '{}' in {} is invalid:\n  {}", name, source, issues.join("\n  ")));
+        }
+        let settings: ClaudeSettings = serde_json::from_value(document)?;
+        providers.push((name.clone(), settings));
+    }
+
+    let (mut imported, mut skipped) = (0, 0);
+    for (name, mut settings) in providers {
+        let Some(target) = resolve_import_name(&name, on_conflict)? else {
+            println!("  - Skipped '{}'", name);
+            skipped += 1;
+            continue;
+        };
+
+        if settings.env.api_key().is_empty() {
+            let token = if interactive {
+                prompt_secret(&format!("  API key for '{}' (redacted in the bundle): ", target))?
+            } else {
+                String::new()
+            };
+            if token.is_empty() {
+                println!("  - Skipped '{}': its token was redacted", name);
+                skipped += 1;
+                continue;
+            }
+            // Put it back where it was taken from: a captured provider authenticates with the API key.
+            match settings.env.extra.get_mut("ANTHROPIC_API_KEY") {
+                Some(key) if settings.env.anthropic_auth_token.is_none() => *key = token,
+                _ => settings.env.anthropic_auth_token = Some(token),
+            }
+        }
+
+        // Other redacted secrets are optional to the provider, but claude must not get them empty.
+        let redacted: Vec<String> =
+            settings.env.extra.iter().filter(|(k, v)| v.is_empty() && is_secret_env_key(k)).map(|(k, _)| k.clone()).collect();
+        for key in redacted {
+            let value = if interactive {
+                prompt_secret(&format!("  {} for '{}' (redacted in the bundle, empty to leave it out): ", key, target))?
+            } else {
+                String::new()
+            };
+            if value.is_empty() {
+                settings.env.extra.remove(&key);
+                println!("  ! '{}' is imported without {}, which was redacted", target, key);
+            } else {
+                settings.env.extra.insert(key, value);
+            }
+        }
+
+        // Only the original name can have been chosen for overwriting; a renamed target must be free.
+        save_provider(&target, &settings, target == name)?;
+        if target == name {
+            println!("  ✓ Imported '{}'", name);
+        } else {
+            println!("  ✓ Imported '{}' as '{}'", name, target);
+        }
+        imported += 1;
+    }
+
+    println!();
+    println!("  {} imported, {} skipped.", imported, skipped);
+    Ok(())
+}
+
+fn build_env_object(env: &EnvSettings) -> serde_json::Map<String, Value> {
+    let mut obj = serde_json::Map::new();
+
+    for (key, value) in &env.extra {
+        obj.insert(key.clone(), Value::String(value.clone()));
+    }
+
+    if let Some(v) = &env.anthropic_base_url {
+        obj.insert("ANTHROPIC_BASE_URL".to_string(), Value::String(v.clone()));
+    }
+    if let Some(v) = &env.anthropic_auth_token {
+        obj.insert("ANTHROPIC_AUTH_TOKEN".to_string(), Value::String(v.clone()));
+    }
+    if let Some(v) = &env.api_timeout_ms {
+        obj.insert("API_TIMEOUT_MS".to_string(), Value::String(v.clone()));
+    }
+    if let Some(v) = env.claude_code_disable_nonessential_traffic {
+        obj.insert("CLAUDE_CODE_DISABLE_NONESSENTIAL_TRAFFIC".to_string(), Value::Number(v.into()));
+    }
+    if let Some(v) = &env.anthropic_model {
+        obj.insert("ANTHROPIC_MODEL".to_string(), Value::String(v.clone()));
+    }
+    if let Some(v) = &env.anthropic_small_fast_model {
+        obj.insert("ANTHROPIC_SMALL_FAST_MODEL".to_string(), Value::String(v.clone()));
+    }
+    if let Some(v) = &env.anthropic_default_sonnet_model {
+        obj.insert("ANTHROPIC_DEFAULT_SONNET_MODEL".to_string(), Value::String(v.clone()));
+    }
+    if let Some(v) = &env.anthropic_default_opus_model {
+        obj.insert("ANTHROPIC_DEFAULT_OPUS_MODEL".to_string(), Value::String(v.clone()));
+    }
+    if let Some(v) = &env.anthropic_default_haiku_model {
+        obj.insert("ANTHROPIC_DEFAULT_HAIKU_MODEL".to_string(), Value::String(v.clone()));
+    }
+
+    obj
+}
+
+/// The reverse of `build_env_object`: known variables go to their typed fields, the rest to extras.
+fn env_settings_from_object(obj: &serde_json::Map<String, Value>) -> EnvSettings {
+    let mut env = EnvSettings::default();
+    for (key, value) in obj {
+        let text = env_value_to_string(value);
+        match key.as_str() {
+            "ANTHROPIC_BASE_URL" => env.anthropic_base_url = Some(text),
+            "ANTHROPIC_AUTH_TOKEN" => env.anthropic_auth_token = Some(text),
+            "API_TIMEOUT_MS" => env.api_timeout_ms = Some(text),
+            "ANTHROPIC_MODEL" => env.anthropic_model = Some(text),
+            "ANTHROPIC_SMALL_FAST_MODEL" => env.anthropic_small_fast_model = Some(text),
+            "ANTHROPIC_DEFAULT_SONNET_MODEL" => env.anthropic_default_sonnet_model = Some(text),
+            "ANTHROPIC_DEFAULT_OPUS_MODEL" => env.anthropic_default_opus_model = Some(text),
+            "ANTHROPIC_DEFAULT_HAIKU_MODEL" => env.anthropic_default_haiku_model = Some(text),
+            "CLAUDE_CODE_DISABLE_NONESSENTIAL_TRAFFIC" if text.parse::<i32>().is_ok() => {
+                env.claude_code_disable_nonessential_traffic = text.parse().ok();
+            }
+            _ => {
+                env.extra.insert(key.clone(), text);
+            }
+        }
+    }
+    env
+}
+
+/// Saves the `"env"` of settings.json as a provider, optionally removing it from settings.json.
+fn capture_command(name: &str, force: bool, strip: bool) -> Result<()> {
+    if let Some(journal) = SwapJournal::load()? {
+        return Err(anyhow!(
+            "settings.json currently holds provider '{}' from a 'settings' mode session (pid {}). \
+             Capture after it ends, or run 'claude-provider recover'.",
+            journal.provider,
+            journal.pid
+        ));
+    }
+
+    let settings_path = get_config_dir()?.join(SETTINGS_FILE);
+    if !settings_path.exists() {
+        return Err(anyhow!("{} does not exist", settings_path.display()));
+    }
+    let mut settings: Value = serde_json::from_str(&fs::read_to_string(&settings_path)?)
+        .with_context(|| format!("Failed to parse {}", settings_path.display()))?;
+    let env_obj = read_settings_env(&settings, &settings_path)?;
+    if env_obj.is_empty() {
+        return Err(anyhow!("{} has no \"env\" variables to capture", settings_path.display()));
+    }
+
+    let mut provider = new_provider_settings(String::new(), String::new(), BTreeMap::new());
+    provider.env = env_settings_from_object(&env_obj);
+    if provider.env.anthropic_base_url.is_none() {
+        return Err(anyhow!("{} has no ANTHROPIC_BASE_URL in \"env\"; a provider needs one", settings_path.display()));
+    }
+    if provider.env.api_key().is_empty() {
+        return Err(anyhow!(
+            "{} has no ANTHROPIC_AUTH_TOKEN or ANTHROPIC_API_KEY in \"env\"; a provider needs one",
+            settings_path.display()
+        ));
+    }
+    let provider_path = save_provider(name, &provider, force)?;
+
+    println!("  ✓ Captured {} variable(s) from {} as '{}'", env_obj.len(), settings_path.display(), name);
+    for key in provider.env.extra.keys() {
+        println!("      {}  (kept as an extra variable)", key);
+    }
+    println!("  ✓ Provider saved to {}", provider_path.display());
+
+    let strip = strip
+        || (io::stdin().is_terminal()
+            && confirm(&format!("  Remove these variables from {} so '{}' is their only source? [y/N] ", settings_path.display(), name))?);
+    if strip {
+        if let Some(root) = settings.as_object_mut() {
+            root.remove("env");
+        }
+        write_atomic(&settings_path, &serde_json::to_string_pretty(&settings)?)?;
+        println!("  ✓ Removed \"env\" from {}", settings_path.display());
+    }
+    Ok(())
+}
+
+/// Expands `~` with the HOME of the user running claude-provider, which for a shared or CI-built
+/// binary isn't the one it was compiled under.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        (None, Some(home)) if path == "~" => home,
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Cache location for `cmd:` output. It lives in `$XDG_RUNTIME_DIR`, which is private to the user and
+/// cleared at logout, so there is deliberately no fallback to a persistent directory.
+fn secret_cache_path(command: &str) -> Option<PathBuf> {
+    use std::hash::{Hash, Hasher};
+
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")?;
+    let mut hasher = std::hash::DefaultHasher::new();
+    command.hash(&mut hasher);
+    Some(PathBuf::from(runtime_dir).join("claude-provider").join(format!("secret-{:016x}", hasher.finish())))
+}
+
+fn read_cached_secret(path: &Path, ttl: Duration) -> Option<String> {
+    let age = fs::metadata(path).ok()?.modified().ok()?.elapsed().ok()?;
+    if age > ttl {
+        return None;
+    }
+    fs::read_to_string(path).ok()
+}
+
+fn write_cached_secret(path: &Path, value: &str) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    file.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+fn run_secret_command(command: &str, cache_ttl: Option<u64>) -> Result<String> {
+    let cache = cache_ttl
+        .filter(|ttl| *ttl > 0)
+        .and_then(|ttl| Some((secret_cache_path(command)?, Duration::from_secs(ttl))));
+    if let Some((path, ttl)) = &cache
+        && let Some(value) = read_cached_secret(path, *ttl)
+    {
+        return Ok(value);
+    }
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run secret command '{}'", command))?;
+    if !output.status.success() {
+        return Err(anyhow!("Secret command '{}' failed with {}", command, output.status));
+    }
+
+    let value = String::from_utf8(output.stdout)
+        .with_context(|| format!("Secret command '{}' printed invalid UTF-8", command))?
+        .trim()
+        .to_string();
+    if value.is_empty() {
+        return Err(anyhow!("Secret command '{}' printed nothing", command));
+    }
+
+    if let Some((path, _)) = &cache {
+        // Caching is best-effort; a failure here only costs another command run next time.
+        let _ = write_cached_secret(path, &value);
+    }
+    Ok(value)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct VaultKdf {
+    algorithm: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+}
+
+impl VaultKdf {
+    fn generate() -> Self {
+        let params = argon2::Params::default();
+        let salt = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        Self {
+            algorithm: "argon2id".to_string(),
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            salt: BASE64.encode(salt),
+        }
+    }
+
+    fn derive_key(&self, secret: &[u8]) -> Result<Zeroizing<[u8; 32]>> {
+        if self.algorithm != "argon2id" {
+            return Err(anyhow!("Unsupported vault key derivation '{}'", self.algorithm));
+        }
+        let params = argon2::Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| anyhow!("Invalid vault key derivation parameters: {}", e))?;
+        let salt = BASE64.decode(&self.salt).context("Invalid vault salt")?;
+
+        let mut key = Zeroizing::new([0u8; 32]);
+        argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password_into(secret, &salt, key.as_mut())
+            .map_err(|e| anyhow!("Failed to derive vault key: {}", e))?;
+        Ok(key)
+    }
+}
+
+/// On-disk vault: a single AEAD-encrypted JSON map of entry name to secret.
+#[derive(Serialize, Deserialize, Debug)]
+struct VaultFile {
+    version: u32,
+    kdf: VaultKdf,
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// An unlocked vault. Secrets only ever exist in memory and are wiped on drop.
+struct Vault {
+    kdf: VaultKdf,
+    key: Zeroizing<[u8; 32]>,
+    entries: BTreeMap<String, String>,
+}
+
+impl Drop for Vault {
+    fn drop(&mut self) {
+        for value in self.entries.values_mut() {
+            value.zeroize();
+        }
+    }
+}
+
+impl Vault {
+    fn path() -> Result<PathBuf> {
+        Ok(get_config_dir()?.join(VAULT_FILE))
+    }
+
+    fn create(secret: &[u8]) -> Result<Self> {
+        let kdf = VaultKdf::generate();
+        let key = kdf.derive_key(secret)?;
+        Ok(Self { kdf, key, entries: BTreeMap::new() })
+    }
+
+    fn unlock_with(secret: &[u8]) -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Err(anyhow!("No vault found. Run 'claude-provider vault init' first."));
+        }
+        let file: VaultFile = serde_json::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        if file.version > VAULT_VERSION {
+            return Err(anyhow!(
+                "{} was written by a newer claude-provider (vault version {})",
+                path.display(),
+                file.version
+            ));
+        }
+        if file.cipher != "xchacha20poly1305" {
+            return Err(anyhow!("Unsupported vault cipher '{}'", file.cipher));
+        }
+
+        let key = file.kdf.derive_key(secret)?;
+        let nonce = BASE64.decode(&file.nonce).context("Invalid vault nonce")?;
+        let ciphertext = BASE64.decode(&file.ciphertext).context("Invalid vault ciphertext")?;
+        if nonce.len() != 24 {
+            return Err(anyhow!("Invalid vault nonce"));
+        }
+
+        let cipher = XChaCha20Poly1305::new(key.as_ref().into());
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(XNonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: VAULT_AAD })
+                .map_err(|_| anyhow!("Could not unlock the vault: wrong passphrase or key file"))?,
+        );
+        let entries = serde_json::from_slice(&plaintext).context("Vault contents are corrupted")?;
+
+        Ok(Self { kdf: file.kdf, key, entries })
+    }
+
+    /// Unlocks with the configured key file, or by asking for the passphrase.
+    fn unlock() -> Result<Self> {
+        let secret = match vault_key_file()? {
+            Some(key_file) => read_vault_key_file(&key_file)?,
+            None => Zeroizing::new(prompt_password("  Vault passphrase: ")?.into_bytes()),
+        };
+        Self::unlock_with(&secret)
+    }
+
+    fn save(&self) -> Result<()> {
+        let plaintext = Zeroizing::new(serde_json::to_vec(&self.entries)?);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let cipher = XChaCha20Poly1305::new(self.key.as_ref().into());
+        let ciphertext = cipher
+            .encrypt(&nonce, Payload { msg: &plaintext, aad: VAULT_AAD })
+            .map_err(|_| anyhow!("Failed to encrypt the vault"))?;
+
+        let file = VaultFile {
+            version: VAULT_VERSION,
+            kdf: self.kdf.clone(),
+            cipher: "xchacha20poly1305".to_string(),
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        };
+        let path = Self::path()?;
+        write_atomic(&path, &serde_json::to_string_pretty(&file)?)?;
+        set_private_permissions(&path)
+    }
+
+    /// Re-derives the key from a new passphrase or key file with a fresh salt.
+    fn rekey(&mut self, secret: &[u8]) -> Result<()> {
+        self.kdf = VaultKdf::generate();
+        self.key = self.kdf.derive_key(secret)?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<&str> {
+        self.entries
+            .get(name)
+            .map(String::as_str)
+            .ok_or_else(|| anyhow!("The vault has no entry named '{}'", name))
+    }
+}
+
+fn set_private_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+fn vault_key_file() -> Result<Option<PathBuf>> {
+    if let Some(path) = std::env::var_os("CLAUDE_PROVIDER_VAULT_KEY_FILE") {
+        return Ok(Some(PathBuf::from(path)));
+    }
+    Ok(ToolConfig::load()?.vault_key_file)
+}
+
+fn read_vault_key_file(path: &Path) -> Result<Zeroizing<Vec<u8>>> {
+    let key = Zeroizing::new(
+        fs::read(path).with_context(|| format!("Failed to read vault key file {}", path.display()))?,
+    );
+    if key.is_empty() {
+        return Err(anyhow!("Vault key file {} is empty", path.display()));
+    }
+    Ok(key)
+}
+
+/// Secret for a new or rotated vault: the key file if one is given, otherwise a passphrase typed twice.
+fn new_vault_secret(key_file: Option<&Path>) -> Result<Zeroizing<Vec<u8>>> {
+    if let Some(path) = key_file {
+        return read_vault_key_file(path);
+    }
+
+    let passphrase = Zeroizing::new(prompt_password("  New vault passphrase: ")?);
+    if passphrase.is_empty() {
+        return Err(anyhow!("Vault passphrase cannot be empty"));
+    }
+    let repeated = Zeroizing::new(prompt_password("  Repeat passphrase: ")?);
+    if *passphrase != *repeated {
+        return Err(anyhow!("Passphrases don't match"));
+    }
+    Ok(Zeroizing::new(passphrase.as_bytes().to_vec()))
+}
+
+fn is_secret_reference(value: &str) -> bool {
+    ["env:", "file:", "cmd:", "vault:"].iter().any(|prefix| value.starts_with(prefix))
+}
+
+/// Resolves secret references for one launch, unlocking the vault at most once.
+struct SecretResolver {
+    cache_ttl: Option<u64>,
+    vault: Option<Vault>,
+}
+
+impl SecretResolver {
+    fn new() -> Result<Self> {
+        Ok(Self { cache_ttl: ToolConfig::load()?.secret_cache_ttl, vault: None })
+    }
+
+    /// Resolves `env:NAME`, `file:PATH`, `cmd:COMMAND` and `vault:ENTRY` references; other values are
+    /// returned as-is.
+    fn resolve(&mut self, value: &str) -> Result<String> {
+        if let Some(name) = value.strip_prefix("env:") {
+            let resolved = std::env::var(name)
+                .map_err(|_| anyhow!("Environment variable '{}' is not set", name))?;
+            if resolved.trim().is_empty() {
+                return Err(anyhow!("Environment variable '{}' is empty", name));
+            }
+            Ok(resolved.trim().to_string())
+        } else if let Some(path) = value.strip_prefix("file:") {
+            let path = expand_home(path);
+            let resolved = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read secret file {}", path.display()))?;
+            if resolved.trim().is_empty() {
+                return Err(anyhow!("Secret file {} is empty", path.display()));
+            }
+            Ok(resolved.trim().to_string())
+        } else if let Some(command) = value.strip_prefix("cmd:") {
+            run_secret_command(command.trim(), self.cache_ttl)
+        } else if let Some(name) = value.strip_prefix("vault:") {
+            if self.vault.is_none() {
+                self.vault = Some(Vault::unlock()?);
+            }
+            let vault = self.vault.as_ref().expect("vault was just unlocked");
+            Ok(vault.get(name.trim())?.to_string())
+        } else {
+            Ok(value.to_string())
+        }
+    }
+}
+
+/// Replaces secret references in the token and extra variables with their values, for launch only.
+fn resolve_env_secrets(env: &mut EnvSettings) -> Result<()> {
+    let mut resolver = SecretResolver::new()?;
+
+    if let Some(token) = &env.anthropic_auth_token {
+        env.anthropic_auth_token =
+            Some(resolver.resolve(token).context("Failed to resolve anthropic_auth_token")?);
+    }
+    for (key, value) in env.extra.iter_mut() {
+        *value = resolver.resolve(value).with_context(|| format!("Failed to resolve {}", key))?;
+    }
+    Ok(())
+}
+
+fn vault_init_command(key_file: Option<PathBuf>) -> Result<()> {
+    let path = Vault::path()?;
+    if path.exists() {
+        return Err(anyhow!("A vault already exists at {}", path.display()));
+    }
+
+    let secret = new_vault_secret(key_file.as_deref())?;
+    Vault::create(&secret)?.save()?;
+
+    let mut config = ToolConfig::load()?;
+    config.vault_key_file = key_file;
+    config.save()?;
+
+    println!("  ✓ Vault created at {}", path.display());
+    Ok(())
+}
+
+fn vault_add_command(entry: &str, from_stdin: bool) -> Result<()> {
+    let mut vault = Vault::unlock()?;
+    let secret = if from_stdin {
+        read_stdin_to_string()?.trim().to_string()
+    } else {
+        prompt_secret(&format!("  Secret for '{}': ", entry))?
+    };
+    if secret.is_empty() {
+        return Err(anyhow!("Secret cannot be empty"));
+    }
+
+    let replaced = vault.entries.insert(entry.to_string(), secret).is_some();
+    vault.save()?;
+
+    if replaced {
+        println!("  ✓ Updated vault entry '{}'", entry);
+    } else {
+        println!("  ✓ Added vault entry '{}' (use it as vault:{})", entry, entry);
+    }
+    Ok(())
+}
+
+fn vault_list_command() -> Result<()> {
+    let vault = Vault::unlock()?;
+    println!();
+    if vault.entries.is_empty() {
+        println!("  The vault is empty.");
+    }
+    for name in vault.entries.keys() {
+        println!("    {}", name);
+    }
+    println!();
+    Ok(())
+}
+
+fn vault_rotate_command(key_file: Option<PathBuf>) -> Result<()> {
+    let mut vault = Vault::unlock()?;
+    let secret = new_vault_secret(key_file.as_deref())?;
+    vault.rekey(&secret)?;
+    vault.save()?;
+
+    let mut config = ToolConfig::load()?;
+    config.vault_key_file = key_file;
+    config.save()?;
+
+    println!("  ✓ Vault re-encrypted with the new {}", if config.vault_key_file.is_some() { "key file" } else { "passphrase" });
+    Ok(())
+}
+
+fn vault_export_command(output: Option<PathBuf>) -> Result<()> {
+    let vault = Vault::unlock()?;
+    let content = Zeroizing::new(serde_json::to_string_pretty(&vault.entries)?);
+
+    match output {
+        Some(path) => {
+            write_atomic(&path, &content)?;
+            eprintln!("  ! {} contains unencrypted secrets; delete it when you are done.", path.display());
+        }
+        None => println!("{}", *content),
+    }
+    Ok(())
+}
+
+/// Moves plaintext provider tokens into the vault and points the providers at `vault:<provider>`.
+/// The plaintext keys `vault migrate` moves: the auth token, and an `ANTHROPIC_API_KEY` kept as an
+/// extra variable by `capture`.
+fn plaintext_provider_keys(env: &mut EnvSettings) -> Vec<&mut String> {
+    env.anthropic_auth_token
+        .iter_mut()
+        .chain(env.extra.get_mut("ANTHROPIC_API_KEY"))
+        .filter(|key| !key.is_empty() && !is_secret_reference(key))
+        .collect()
+}
+
+fn vault_migrate_command(yes: bool) -> Result<()> {
+    let mut candidates = Vec::new();
+    for name in list_providers()? {
+        let mut settings = load_provider(&name)?;
+        if !plaintext_provider_keys(&mut settings.env).is_empty() {
+            candidates.push((name, settings, Vec::new()));
+        }
+    }
+
+    if candidates.is_empty() {
+        println!("  No providers with plaintext tokens.");
+        return Ok(());
+    }
+
+    println!();
+    println!("  Tokens of these providers will be moved into the vault:");
+    for (name, _, _) in &candidates {
+        println!("    {}", name);
+    }
+    println!();
+    if !yes && !confirm("  Continue? [y/N] ")? {
+        println!("  Aborted.");
+        return Ok(());
+    }
+
+    let mut vault = Vault::unlock()?;
+    for (name, settings, references) in &mut candidates {
+        for key in plaintext_provider_keys(&mut settings.env) {
+            let token = std::mem::take(key);
+            let mut entry = name.clone();
+            let mut suffix = 2;
+            while vault.entries.get(&entry).is_some_and(|existing| *existing != token) {
+                entry = format!("{}-{}", name, suffix);
+                suffix += 1;
+            }
+            vault.entries.insert(entry.clone(), token);
+            *key = format!("vault:{}", entry);
+            references.push(key.clone());
+        }
+    }
+
+    // Persist the vault before dropping the plaintext copies from the provider files.
+    vault.save()?;
+    for (name, settings, references) in &candidates {
+        let path = provider_path(name)?;
+        write_atomic(&path, &serde_json::to_string_pretty(settings)?)?;
+        // Backups from schema upgrades still hold the plaintext token.
+        for backup in provider_backups(path.parent().unwrap_or(Path::new(".")), Some(name)) {
+            fs::remove_file(backup)?;
+        }
+        println!("  ✓ {} now uses {}", name, references.join(", "));
+    }
+
+    Ok(())
+}
+
+/// What a settings-mode launch does to each variable in the existing `settings.json` env.
+#[derive(Debug, Default)]
+struct EnvMergePlan {
+    /// Final value for every key the provider contributes
+    set: serde_json::Map<String, Value>,
+    /// Existing keys that get dropped (only with `replace`)
+    removed: Vec<String>,
+    /// Existing keys whose value the provider replaces
+    overridden: Vec<String>,
+    /// Existing keys that win over the provider's value (only with `merge-preserve`)
+    preserved: Vec<String>,
+}
+
+fn plan_env_merge(
+    current_env: &serde_json::Map<String, Value>,
+    provider_env: serde_json::Map<String, Value>,
+    strategy: EnvMergeStrategy,
+) -> EnvMergePlan {
+    let mut plan = EnvMergePlan::default();
+
+    if strategy == EnvMergeStrategy::Replace {
+        plan.removed = current_env.keys().filter(|k| !provider_env.contains_key(*k)).cloned().collect();
+    }
+
+    for (key, value) in provider_env {
+        match current_env.get(&key) {
+            Some(existing) if strategy == EnvMergeStrategy::MergePreserve => {
+                if *existing != value {
+                    plan.preserved.push(key);
+                }
+            }
+            Some(existing) => {
+                if *existing != value {
+                    plan.overridden.push(key.clone());
+                }
+                plan.set.insert(key, value);
+            }
+            None => {
+                plan.set.insert(key, value);
+            }
+        }
+    }
+
+    plan
+}
+
+fn read_settings_env(settings: &Value, settings_path: &Path) -> Result<serde_json::Map<String, Value>> {
+    match settings.get("env") {
+        Some(Value::Object(env)) => Ok(env.clone()),
+        Some(_) => Err(anyhow!("\"env\" in {} must be a JSON object", settings_path.display())),
+        None => Ok(serde_json::Map::new()),
+    }
+}
+
+fn env_value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn launch_claude(args: &[String], env_obj: &serde_json::Map<String, Value>) -> Result<ExitStatus> {
+    disable_raw_mode().ok();
+
+    let status = Command::new("claude")
+        .args(args)
+        .envs(env_obj.iter().map(|(k, v)| (k, env_value_to_string(v))))
+        .status()
+        .context("Failed to execute claude");
+
+    enable_raw_mode().ok();
+    status
+}
+
+/// New files are created private: most of what goes through here holds a token. An existing file
+/// keeps its permissions.
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // Follow symlinks so dotfile-managed files stay links to their real location.
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let tmp_path = path.with_extension("tmp");
+    // A leftover from a crash would keep whatever mode it was created with.
+    let _ = fs::remove_file(&tmp_path);
+    {
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        // Keep the permissions of the file being replaced, e.g. a private provider file.
+        if let Ok(metadata) = fs::metadata(&path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+    }
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+fn process_alive(pid: u32) -> bool {
+    let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    rc == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn json_get_path<'a>(root: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(root, |value, key| value.as_object()?.get(key))
+}
+
+fn json_set_path(root: &mut Value, path: &[String], value: Option<Value>) {
+    let Some((last, parents)) = path.split_last() else {
+        return;
+    };
+
+    let mut current = root;
+    for key in parents {
+        if value.is_none() && current.get(key).is_none() {
+            return;
+        }
+        let Some(obj) = current.as_object_mut() else {
+            return;
+        };
+        let child = obj.entry(key.clone()).or_insert_with(|| Value::Object(serde_json::Map::new()));
+        if !child.is_object() {
+            *child = Value::Object(serde_json::Map::new());
+        }
+        current = child;
+    }
+
+    if let Some(obj) = current.as_object_mut() {
+        match value {
+            Some(v) => {
+                obj.insert(last.clone(), v);
+            }
+            None => {
+                obj.remove(last);
+            }
+        }
+    }
+}
+
+/// One value a session wrote into `settings.json`, alongside what was there before.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PatchEntry {
+    path: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    injected: Option<Value>,
+}
+
+/// The exact set of changes a session makes to `settings.json`. Reverting it is a three-way merge:
+/// only values still holding what we injected are put back, so edits made during the session survive.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SettingsPatch {
+    entries: Vec<PatchEntry>,
+    /// Objects that did not exist before the session and are dropped again if left empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    created: Vec<Vec<String>>,
+}
+
+impl SettingsPatch {
+    /// Records that `path` should hold `injected` (or be removed) for the session.
+    fn set(&mut self, settings: &Value, path: Vec<String>, injected: Option<Value>) {
+        let original = json_get_path(settings, &path).cloned();
+        if original == injected {
+            return;
+        }
+
+        if injected.is_some() {
+            for depth in 1..path.len() {
+                let prefix = &path[..depth];
+                if json_get_path(settings, prefix).is_none() && !self.created.iter().any(|c| c == prefix) {
+                    self.created.push(prefix.to_vec());
+                }
+            }
+        }
+
+        self.entries.push(PatchEntry { path, original, injected });
+    }
+
+    fn apply(&self, settings: &mut Value) {
+        for entry in &self.entries {
+            json_set_path(settings, &entry.path, entry.injected.clone());
+        }
+    }
+
+    /// Undoes the patch on the current settings and returns the paths that were edited during the
+    /// session and therefore left alone.
+    fn revert(&self, settings: &mut Value) -> Vec<String> {
+        let mut conflicts = Vec::new();
+
+        for entry in self.entries.iter().rev() {
+            let current = json_get_path(settings, &entry.path);
+            if current == entry.injected.as_ref() {
+                json_set_path(settings, &entry.path, entry.original.clone());
+            } else if current != entry.original.as_ref() {
+                conflicts.push(entry.path.join("."));
+            }
+        }
+
+        let mut created: Vec<&Vec<String>> = self.created.iter().collect();
+        created.sort_by_key(|path| std::cmp::Reverse(path.len()));
+        for path in created {
+            if json_get_path(settings, path).and_then(|v| v.as_object()).is_some_and(|obj| obj.is_empty()) {
+                json_set_path(settings, path, None);
+            }
+        }
+
+        conflicts
+    }
+}
+
+fn report_conflicts(conflicts: &[String]) {
+    for path in conflicts {
+        eprintln!("  ! {} was changed during the session; kept the new value instead of restoring it.", path);
+    }
+}
+
+/// Written before `settings.json` is swapped so an interrupted session can be undone later.
+#[derive(Serialize, Deserialize, Debug)]
+struct SwapJournal {
+    pid: u32,
+    provider: String,
+    settings_path: PathBuf,
+    original_settings: String,
+    patch: SettingsPatch,
+}
+
+impl SwapJournal {
+    fn path() -> Result<PathBuf> {
+        Ok(get_config_dir()?.join(JOURNAL_FILE))
+    }
+
+    fn load() -> Result<Option<Self>> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)?;
+        let journal = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse session journal {}", path.display()))?;
+        Ok(Some(journal))
+    }
+
+    fn save(&self) -> Result<()> {
+        write_atomic(&Self::path()?, &serde_json::to_string_pretty(self)?)
+    }
+
+    fn is_stale(&self) -> bool {
+        !process_alive(self.pid)
+    }
+
+    /// Reverts the session's changes on top of whatever `settings.json` holds now, returning conflicts.
+    fn restore(&self) -> Result<Vec<String>> {
+        let mut conflicts = Vec::new();
+
+        if self.settings_path.exists() {
+            let current_content = fs::read_to_string(&self.settings_path)?;
+            let mut current: Value = serde_json::from_str(&current_content).with_context(|| {
+                format!(
+                    "{} is no longer valid JSON; fix it and run 'claude-provider recover'",
+                    self.settings_path.display()
+                )
+            })?;
+            conflicts = self.patch.revert(&mut current);
+
+            // Keep the original formatting when nothing else changed.
+            let original: Value = serde_json::from_str(&self.original_settings)?;
+            let content = if current == original {
+                self.original_settings.clone()
+            } else {
+                serde_json::to_string_pretty(&current)?
+            };
+            write_atomic(&self.settings_path, &content)
+        } else {
+            write_atomic(&self.settings_path, &self.original_settings)
+        }
+        .with_context(|| format!("Failed to restore {}", self.settings_path.display()))?;
+
+        fs::remove_file(Self::path()?)?;
+        Ok(conflicts)
+    }
+}
+
+/// A journaled `settings.json` swap that is undone exactly once, whichever of the normal exit path,
+/// the signal watcher or a panic gets there first.
+struct SettingsSwap {
+    journal: SwapJournal,
+    restored: Mutex<bool>,
+}
+
+impl SettingsSwap {
+    fn begin(journal: SwapJournal, modified_content: &str) -> Result<Arc<Self>> {
+        if let Some(existing) = SwapJournal::load()? {
+            if !existing.is_stale() {
+                return Err(anyhow!(
+                    "Provider '{}' is already swapped into settings.json by another session (pid {}). Use --mode env to run both.",
+                    existing.provider,
+                    existing.pid
+                ));
+            }
+            report_conflicts(&existing.restore()?);
+        }
+
+        journal.save()?;
+        write_atomic(&journal.settings_path, modified_content)?;
+
+        Ok(Arc::new(Self { journal, restored: Mutex::new(false) }))
+    }
+
+    fn restore(&self) -> Result<()> {
+        let mut restored = self.restored.lock().unwrap_or_else(|e| e.into_inner());
+        if !*restored {
+            report_conflicts(&self.journal.restore()?);
+            *restored = true;
+        }
+        Ok(())
+    }
+}
+
+struct SwapGuard(Arc<SettingsSwap>);
+
+impl Drop for SwapGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.restore() {
+            eprintln!("Error: {:#}", e);
+        }
+    }
+}
+
+fn launch_claude_with_swap(args: &[String], swap: &Arc<SettingsSwap>) -> Result<ExitStatus> {
+    disable_raw_mode().ok();
+
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    let signals_handle = signals.handle();
+
+    let mut child = match Command::new("claude").args(args).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            enable_raw_mode().ok();
+            return Err(e).context("Failed to execute claude");
+        }
+    };
+    let child_pid = child.id();
+    let child_done = Arc::new(AtomicBool::new(false));
+
+    let watcher = {
+        let swap = Arc::clone(swap);
+        let child_done = Arc::clone(&child_done);
+        thread::spawn(move || {
+            for sig in signals.forever() {
+                // Ctrl-C reaches claude through the terminal; it decides whether to quit.
+                if sig == SIGINT {
+                    continue;
+                }
+
+                unsafe {
+                    libc::kill(child_pid as libc::pid_t, sig);
+                }
+
+                let mut waited = Duration::ZERO;
+                while waited < SIGNAL_GRACE_PERIOD {
+                    if child_done.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                    waited += Duration::from_millis(100);
+                }
+
+                if let Err(e) = swap.restore() {
+                    eprintln!("Error: {:#}", e);
+                }
+                std::process::exit(128 + sig);
+            }
+        })
+    };
+
+    let status = child.wait().context("Failed to wait for claude");
+    child_done.store(true, Ordering::SeqCst);
+    signals_handle.close();
+    let _ = watcher.join();
+
+    enable_raw_mode().ok();
+    status
+}
+
+/// Everything besides `env` that the provider wants in `settings.json` for the session
+/// (plugins, thinking mode, permissions, hooks, model, statusLine, ...).
+fn build_settings_overlay(provider_settings: &ClaudeSettings) -> serde_json::Map<String, Value> {
+    let mut overlay = serde_json::Map::new();
+
+    if let Some(other) = provider_settings.other.as_object() {
+        for (key, value) in other {
+            overlay.insert(key.clone(), value.clone());
+        }
+    }
+    let has_plugins = match &provider_settings.enabled_plugins {
+        Value::Null => false,
+        Value::Object(plugins) => !plugins.is_empty(),
+        _ => true,
+    };
+    if has_plugins {
+        overlay.insert("enabledPlugins".to_string(), provider_settings.enabled_plugins.clone());
+    }
+    if let Some(enabled) = provider_settings.always_thinking_enabled {
+        overlay.insert("alwaysThinkingEnabled".to_string(), Value::Bool(enabled));
+    }
+
+    overlay
+}
+
+/// Deep-merges `overlay` into the patch: objects are merged key by key, anything else replaces the
+/// existing value at that path.
+fn patch_overlay(patch: &mut SettingsPatch, settings: &Value, path: Vec<String>, overlay: &Value) {
+    let existing = json_get_path(settings, &path);
+    match overlay {
+        Value::Object(fields) if existing.is_none_or(|v| v.is_object()) => {
+            for (key, value) in fields {
+                let mut child = path.clone();
+                child.push(key.clone());
+                patch_overlay(patch, settings, child, value);
+            }
+        }
+        _ => patch.set(settings, path, Some(overlay.clone())),
+    }
+}
+
+fn run_with_settings_swap(
+    provider_name: &str,
+    env_obj: serde_json::Map<String, Value>,
+    overlay: serde_json::Map<String, Value>,
+    strategy: EnvMergeStrategy,
+    args: &[String],
+) -> Result<ExitStatus> {
+    let config_dir = get_config_dir()?;
+    let settings_path = config_dir.join(SETTINGS_FILE);
+    let settings_content = fs::read_to_string(&settings_path)
+        .with_context(|| format!("Failed to read {}; run 'claude-provider doctor'", settings_path.display()))?;
+    let mut settings: Value = serde_json::from_str(&settings_content)
+        .with_context(|| format!("Failed to parse {}; run 'claude-provider doctor'", settings_path.display()))?;
+    if !settings.is_object() {
+        return Err(anyhow!("{} must contain a JSON object", settings_path.display()));
+    }
+
+    let current_env = read_settings_env(&settings, &settings_path)?;
+    let plan = plan_env_merge(&current_env, env_obj, strategy);
+
+    let mut patch = SettingsPatch::default();
+    for (key, value) in &overlay {
+        patch_overlay(&mut patch, &settings, vec![key.clone()], value);
+    }
+    for key in plan.removed {
+        patch.set(&settings, vec!["env".to_string(), key], None);
+    }
+    for (key, value) in plan.set {
+        patch.set(&settings, vec!["env".to_string(), key], Some(value));
+    }
+    patch.apply(&mut settings);
+
+    let modified_content = serde_json::to_string_pretty(&settings)?;
+
+    let journal = SwapJournal {
+        pid: std::process::id(),
+        provider: provider_name.to_string(),
+        settings_path,
+        original_settings: settings_content,
+        patch,
+    };
+    let swap = SettingsSwap::begin(journal, &modified_content)?;
+    let guard = SwapGuard(Arc::clone(&swap));
+
+    let status = launch_claude_with_swap(args, &swap);
+
+    swap.restore()?;
+    drop(guard);
+
+    status
+}
+
+/// Restores `settings.json` from a journal left behind by a session that no longer exists.
+fn recover_stale_session() -> Result<()> {
+    let Some(journal) = SwapJournal::load()? else {
+        return Ok(());
+    };
+    if !journal.is_stale() {
+        return Ok(());
+    }
+
+    eprintln!(
+        "  ! A previous session with provider '{}' (pid {}) ended without restoring {}.",
+        journal.provider,
+        journal.pid,
+        journal.settings_path.display()
+    );
+
+    if io::stdin().is_terminal() {
+        let answer = prompt_input("  Restore the original settings now? [Y/n] ")?;
+        if answer.eq_ignore_ascii_case("n") || answer.eq_ignore_ascii_case("no") {
+            eprintln!("  Skipped. Run 'claude-provider recover' to restore later.");
+            return Ok(());
+        }
+    }
+
+    report_conflicts(&journal.restore()?);
+    eprintln!("  ✓ Restored {}", journal.settings_path.display());
+    Ok(())
+}
+
+fn recover_command(force: bool) -> Result<()> {
+    let Some(journal) = SwapJournal::load()? else {
+        println!();
+        println!("  Nothing to recover.");
+        println!();
+        return Ok(());
+    };
+
+    if !journal.is_stale() && !force {
+        return Err(anyhow!(
+            "Provider '{}' is still in use by pid {}. Pass --force to restore anyway.",
+            journal.provider,
+            journal.pid
+        ));
+    }
+
+    report_conflicts(&journal.restore()?);
+    println!();
+    println!("  ✓ Restored {} from the session journal for '{}'", journal.settings_path.display(), journal.provider);
+    println!();
+    Ok(())
+}
+
+fn resolve_launch_mode(requested: Option<LaunchMode>, provider_settings: &ClaudeSettings) -> Result<LaunchMode> {
+    if let Some(mode) = requested.or(provider_settings.launch_mode) {
+        return Ok(mode);
+    }
+    Ok(ToolConfig::load()?.launch_mode.unwrap_or_default())
+}
+
+fn resolve_env_merge(provider_settings: &ClaudeSettings) -> Result<EnvMergeStrategy> {
+    if let Some(strategy) = provider_settings.env_merge {
+        return Ok(strategy);
+    }
+    Ok(ToolConfig::load()?.env_merge.unwrap_or_default())
+}
+
+/// Copies `load_provider` keeps before upgrading a schema (`<name>.json.v<N>.bak`), of one provider
+/// or of all. They hold the same secrets as the provider file.
+fn provider_backups(dir: &Path, name: Option<&str>) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|path| {
+            let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            let Some(stem) = file_name.strip_suffix(".bak") else {
+                return false;
+            };
+            match name {
+                Some(name) => stem.strip_prefix(name).is_some_and(|rest| rest.starts_with(".json.v")),
+                None => stem.contains(".json.v"),
+            }
+        })
+        .collect()
+}
+
+fn load_provider(provider_name: &str) -> Result<ClaudeSettings> {
+    validate_provider_name(provider_name)?;
+    let provider_path = provider_path(provider_name)?;
+
+    if !provider_path.exists() {
+        return Err(anyhow!("Provider '{}' not found. Run 'claude-provider setup' first.", provider_name));
+    }
+
+    let provider_content = fs::read_to_string(&provider_path)?;
+    let mut doc: Value = serde_json::from_str(&provider_content)
+        .with_context(|| format!("Failed to parse {}", provider_path.display()))?;
+    let version = upgrade_provider_document(&mut doc, &provider_path.display().to_string())?;
+    let provider_settings: ClaudeSettings = serde_json::from_value(doc)
+        .with_context(|| format!("Failed to parse {}", provider_path.display()))?;
+
+    if version < PROVIDER_SCHEMA_VERSION {
+        if ToolConfig::load()?.migration_backups.unwrap_or(true) {
+            let backup = provider_path.with_extension(format!("json.v{}.bak", version));
+            fs::copy(&provider_path, &backup)
+                .with_context(|| format!("Failed to back up {} before upgrading it", provider_path.display()))?;
+            set_private_permissions(&backup)?;
+        }
+        write_atomic(&provider_path, &serde_json::to_string_pretty(&provider_settings)?)?;
+        eprintln!(
+            "  ✓ Upgraded provider '{}' from schema version {} to {}",
+            provider_name, version, PROVIDER_SCHEMA_VERSION
+        );
+    }
+    Ok(provider_settings)
+}
+
+fn run_with_provider(provider_name: &str, args: &[String], mode: Option<LaunchMode>) -> Result<()> {
+    let mut provider_settings = load_provider(provider_name)?;
+    resolve_env_secrets(&mut provider_settings.env)
+        .with_context(|| format!("Provider '{}' has a secret that could not be resolved", provider_name))?;
+
+    let env_obj = build_env_object(&provider_settings.env);
+    let overlay = build_settings_overlay(&provider_settings);
+
+    let launch_mode = resolve_launch_mode(mode, &provider_settings)?;
+    let strategy = resolve_env_merge(&provider_settings)?;
+    let mut claude_args = Vec::new();
+    if launch_mode == LaunchMode::Env {
+        check_settings_env_shadowing(provider_name, &env_obj)?;
+        // claude layers --settings over the user's settings.json without either touching disk.
+        if !overlay.is_empty() {
+            claude_args.push("--settings".to_string());
+            claude_args.push(serde_json::to_string(&overlay)?);
+        }
+        claude_args.extend(args.iter().cloned());
+    }
+
+    // Only now: `cmd:` secrets may prompt on the terminal and the warnings above need line endings.
+    enable_raw_mode().context("Failed to enable raw mode")?;
+    let status = match launch_mode {
+        LaunchMode::Env => launch_claude(&claude_args, &env_obj),
+        LaunchMode::Settings => run_with_settings_swap(provider_name, env_obj, overlay, strategy, args),
+    };
+    disable_raw_mode().ok();
+
+    if !status?.success() {
+        return Err(anyhow!("claude exited with non-zero status"));
+    }
+
+    Ok(())
+}
+
+/// In `env` mode claude applies the settings.json `"env"` over the variables it inherits, so a value
+/// left there by hand would silently win over the provider's. A different endpoint or token means
+/// talking to the wrong provider, so that refuses to launch; other variables only warn.
+fn check_settings_env_shadowing(provider_name: &str, env_obj: &serde_json::Map<String, Value>) -> Result<()> {
+    let settings_path = get_config_dir()?.join(SETTINGS_FILE);
+    let Some(settings) = fs::read_to_string(&settings_path).ok().and_then(|c| serde_json::from_str::<Value>(&c).ok())
+    else {
+        return Ok(());
+    };
+    let Ok(current_env) = read_settings_env(&settings, &settings_path) else {
+        return Ok(());
+    };
+
+    let shadowed: Vec<&String> = env_obj
+        .iter()
+        .filter(|(key, value)| current_env.get(*key).is_some_and(|v| env_value_to_string(v) != env_value_to_string(value)))
+        .map(|(key, _)| key)
+        .collect();
+    if shadowed.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<&str> = shadowed.iter().map(|key| key.as_str()).collect();
+    if names.iter().any(|key| ["ANTHROPIC_BASE_URL", "ANTHROPIC_AUTH_TOKEN", "ANTHROPIC_API_KEY"].contains(key)) {
+        return Err(anyhow!(
+            "{} sets {} in \"env\", which claude would use instead of provider '{}'.\n\
+             Move them into a provider with 'claude-provider capture <name> --strip', remove them by hand, \
+             or launch with --mode settings.",
+            settings_path.display(),
+            names.join(", "),
+            provider_name
+        ));
+    }
+    eprintln!(
+        "  ! {} sets {} in \"env\", which claude uses instead of the values of provider '{}'.",
+        settings_path.display(),
+        names.join(", "),
+        provider_name
+    );
+    Ok(())
+}
+
+fn overrides_command(provider_name: &str, strategy: Option<EnvMergeStrategy>) -> Result<()> {
+    let provider_settings = load_provider(provider_name)?;
+    let strategy = match strategy {
+        Some(strategy) => strategy,
+        None => resolve_env_merge(&provider_settings)?,
+    };
+
+    let settings_path = get_config_dir()?.join(SETTINGS_FILE);
+    let current_env = if settings_path.exists() {
+        let settings: Value = serde_json::from_str(&fs::read_to_string(&settings_path)?)
+            .with_context(|| format!("Failed to parse {}", settings_path.display()))?;
+        read_settings_env(&settings, &settings_path)?
+    } else {
+        serde_json::Map::new()
+    };
+
+    let plan = plan_env_merge(&current_env, build_env_object(&provider_settings.env), strategy);
+
+    println!();
+    println!("  Env merge for '{}' using '{}':", provider_name, strategy.as_str());
+    println!();
+    if plan.overridden.is_empty() && plan.removed.is_empty() && plan.preserved.is_empty() {
+        println!("    No existing settings.json env variables are affected.");
+    }
+    for key in &plan.overridden {
+        println!("    overridden  {}", key);
+    }
+    for key in &plan.removed {
+        println!("    removed     {}", key);
+    }
+    for key in &plan.preserved {
+        println!("    preserved   {}  (provider value ignored)", key);
+    }
+    if resolve_launch_mode(None, &provider_settings)? == LaunchMode::Env {
+        println!();
+        println!("  Note: this provider launches in 'env' mode, which leaves settings.json alone;");
+        println!("  claude then applies the settings.json env on top of the provider's variables.");
+    }
+    println!();
+
+    Ok(())
+}
+
+/// Honours a proxy configured on the provider the same way claude would, falling back to the
+/// proxy variables of the current environment.
+fn api_agent(env: &EnvSettings) -> Result<ureq::Agent> {
+    let builder = ureq::AgentBuilder::new().timeout(API_TIMEOUT);
+    let proxy = ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]
+        .iter()
+        .find_map(|key| env.extra.get(*key));
+    let builder = match proxy {
+        Some(proxy) => builder.proxy(ureq::Proxy::new(proxy).with_context(|| format!("Invalid proxy '{}'", proxy))?),
+        None => builder.try_proxy_from_env(true),
+    };
+    Ok(builder.build())
+}
+
+/// Claude Code appends the API path to ANTHROPIC_BASE_URL, so do the same.
+fn api_url(base_url: &str, path: &str) -> String {
+    format!("{}{}", base_url.trim_end_matches('/'), path)
+}
+
+/// Sends both auth headers: ANTHROPIC_AUTH_TOKEN is a bearer token, but some gateways only look at
+/// x-api-key.
+fn api_request(agent: &ureq::Agent, method: &str, url: &str, token: &str) -> ureq::Request {
+    agent
+        .request(method, url)
+        .set("authorization", &format!("Bearer {}", token))
+        .set("x-api-key", token)
+        .set("anthropic-version", ANTHROPIC_VERSION)
+}
+
+fn api_error_message(body: &str) -> String {
+    serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|v| v.pointer("/error/message").and_then(Value::as_str).map(str::to_string))
+        .unwrap_or_else(|| body.chars().take(200).collect::<String>().trim().to_string())
+}
+
+fn describe_api_failure(status: u16, body: &str) -> String {
+    let message = api_error_message(body);
+    match status {
+        401 | 403 => format!("authentication failed: {}", message),
+        400 | 404 if message.to_ascii_lowercase().contains("model") => format!("unknown model: {}", message),
+        404 => format!("endpoint not found, check the base URL: {}", message),
+        _ => format!("HTTP {}: {}", status, message),
+    }
+}
+
+/// Lists the model IDs of an Anthropic-style `/v1/models` endpoint, following pagination.
+fn list_models(agent: &ureq::Agent, base_url: &str, token: &str) -> Result<Vec<String>> {
+    let mut models = Vec::new();
+    let mut after_id: Option<String> = None;
+    loop {
+        let mut request = api_request(agent, "GET", &api_url(base_url, "/v1/models"), token).query("limit", "1000");
+        if let Some(after_id) = &after_id {
+            request = request.query("after_id", after_id);
+        }
+        let body = match request.call() {
+            Ok(response) => response.into_string()?,
+            Err(ureq::Error::Status(404, _)) => return Err(anyhow!("the provider does not support listing models")),
+            Err(ureq::Error::Status(status, response)) => {
+                return Err(anyhow!(describe_api_failure(status, &response.into_string().unwrap_or_default())));
+            }
+            Err(e) => return Err(anyhow!("connection failed: {}", e)),
+        };
+
+        let page: Value = serde_json::from_str(&body).context("the models endpoint did not return JSON")?;
+        let data = page
+            .get("data")
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow!("the models endpoint returned an unexpected response"))?;
+        models.extend(data.iter().filter_map(|m| m.get("id").and_then(Value::as_str)).map(str::to_string));
+
+        let has_more = page.get("has_more").and_then(Value::as_bool).unwrap_or(false);
+        match page.get("last_id").and_then(Value::as_str) {
+            Some(last_id) if has_more && after_id.as_deref() != Some(last_id) => after_id = Some(last_id.to_string()),
+            _ => return Ok(models),
+        }
+    }
+}
+
+struct ProbeResult {
+    status: Option<u16>,
+    latency: Duration,
+    error: Option<String>,
+}
+
+/// Sends the smallest possible Messages API request. With `stream`, success also requires an SSE
+/// response whose first event arrives; latency is then time to that first event.
+fn probe_messages(agent: &ureq::Agent, base_url: &str, token: &str, model: &str, stream: bool) -> ProbeResult {
+    let body = serde_json::json!({
+        "model": model,
+        "max_tokens": 1,
+        "stream": stream,
+        "messages": [{ "role": "user", "content": "ping" }],
+    });
+    let started = std::time::Instant::now();
+    let response = api_request(agent, "POST", &api_url(base_url, "/v1/messages"), token)
+        .set("content-type", "application/json")
+        .send_string(&body.to_string());
+
+    let response = match response {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            return ProbeResult {
+                status: Some(status),
+                latency: started.elapsed(),
+                error: Some(describe_api_failure(status, &body)),
+            };
+        }
+        Err(e) => {
+            return ProbeResult { status: None, latency: started.elapsed(), error: Some(format!("connection failed: {}", e)) };
+        }
+    };
+    let status = Some(response.status());
+
+    if !stream {
+        let _ = response.into_string();
+        return ProbeResult { status, latency: started.elapsed(), error: None };
+    }
+
+    if !response.content_type().contains("text/event-stream") {
+        let error = format!("expected an event stream, got '{}'", response.content_type());
+        return ProbeResult { status, latency: started.elapsed(), error: Some(error) };
+    }
+    let mut reader = io::BufReader::new(response.into_reader());
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match io::BufRead::read_line(&mut reader, &mut line) {
+            Ok(0) => {
+                let error = Some("stream ended before the first event".to_string());
+                return ProbeResult { status, latency: started.elapsed(), error };
+            }
+            Ok(_) if line.starts_with("event:") || line.starts_with("data:") => {
+                return ProbeResult { status, latency: started.elapsed(), error: None };
+            }
+            Ok(_) => {}
+            Err(e) => {
+                let error = Some(format!("stream failed: {}", e));
+                return ProbeResult { status, latency: started.elapsed(), error };
+            }
+        }
+    }
+}
+
+/// Every distinct model configured for the provider, in slot order.
+fn configured_models(env: &EnvSettings) -> Vec<String> {
+    let mut models: Vec<String> = Vec::new();
+    for model in [
+        &env.anthropic_model,
+        &env.anthropic_default_sonnet_model,
+        &env.anthropic_default_opus_model,
+        &env.anthropic_default_haiku_model,
+        &env.anthropic_small_fast_model,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if !model.is_empty() && !models.contains(model) {
+            models.push(model.clone());
+        }
+    }
+    models
+}
+
+fn print_probe(label: &str, result: &ProbeResult) {
+    let status = result.status.map(|s| s.to_string()).unwrap_or_else(|| "---".to_string());
+    let latency = format!("{} ms", result.latency.as_millis());
+    match &result.error {
+        None => println!("    ✓ {:<28} {}  {:>8}", label, status, latency),
+        Some(error) => println!("    ✗ {:<28} {}  {:>8}  {}", label, status, latency, error),
+    }
+}
+
+/// Checks connectivity, auth, every configured model and streaming; returns whether all passed.
+fn test_provider(name: &str) -> Result<bool> {
+    let mut settings = load_provider(name)?;
+    resolve_env_secrets(&mut settings.env)
+        .with_context(|| format!("Provider '{}' has a secret that could not be resolved", name))?;
+    let agent = &api_agent(&settings.env)?;
+
+    let base_url = settings.env.anthropic_base_url.clone().unwrap_or_default();
+    let token = settings.env.api_key().to_string();
+
+    println!();
+    println!("  {}  {}", name, base_url);
+
+    let mut models = configured_models(&settings.env);
+    if models.is_empty() {
+        models.push(FALLBACK_TEST_MODEL.to_string());
+    }
+
+    let mut ok = true;
+    for model in &models {
+        let result = probe_messages(agent, &base_url, &token, model, false);
+        print_probe(model, &result);
+        ok &= result.error.is_none();
+
+        // Auth and connection failures won't change per model; don't repeat them.
+        if matches!(result.status, None | Some(401) | Some(403)) {
+            return Ok(false);
+        }
+    }
+
+    let result = probe_messages(agent, &base_url, &token, &models[0], true);
+    print_probe("streaming", &result);
+    ok &= result.error.is_none();
+
+    Ok(ok)
+}
+
+fn test_command(names: &[String], all: bool) -> Result<()> {
+    let names = if all {
+        list_providers()?
+    } else if names.is_empty() {
+        return Err(anyhow!("Name at least one provider to test, or pass --all"));
+    } else {
+        resolve_provider_patterns(names)?
+    };
+
+    let mut failed = Vec::new();
+    for name in &names {
+        match test_provider(name) {
+            Ok(true) => {}
+            Ok(false) => failed.push(name.as_str()),
+            Err(e) => {
+                println!();
+                println!("  {}", name);
+                println!("    ✗ {:#}", e);
+                failed.push(name.as_str());
+            }
+        }
+    }
+    println!();
+
+    if !failed.is_empty() {
+        return Err(anyhow!("{} of {} provider(s) failed: {}", failed.len(), names.len(), failed.join(", ")));
+    }
+    Ok(())
+}
+
+/// One problem found by `doctor`, with an automatic fix when there is a safe one.
+struct Finding {
+    problem: String,
+    hint: String,
+    fix: Option<Box<dyn FnOnce() -> Result<()>>>,
+}
+
+impl Finding {
+    fn manual(problem: String, hint: impl Into<String>) -> Self {
+        Finding { problem, hint: hint.into(), fix: None }
+    }
+
+    fn fixable(problem: String, hint: impl Into<String>, fix: impl FnOnce() -> Result<()> + 'static) -> Self {
+        Finding { problem, hint: hint.into(), fix: Some(Box::new(fix)) }
+    }
+}
+
+fn doctor_ok(message: &str) {
+    println!("  ✓ {}", message);
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0))
+}
+
+fn is_private(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|m| m.permissions().mode() & 0o077 == 0)
+}
+
+fn check_claude_binary(findings: &mut Vec<Finding>) {
+    let Some(path) = find_in_path("claude") else {
+        findings.push(Finding::manual(
+            "'claude' is not on PATH, so providers can't be launched".to_string(),
+            "install Claude Code (npm install -g @anthropic-ai/claude-code) or add it to PATH",
+        ));
+        return;
+    };
+    match Command::new(&path).arg("--version").output() {
+        Ok(output) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            doctor_ok(&format!("claude {} ({})", version.lines().next().unwrap_or_default().trim(), path.display()));
+        }
+        _ => findings.push(Finding::manual(
+            format!("'{} --version' failed", path.display()),
+            "check that your Claude Code installation works",
+        )),
+    }
+}
+
+fn check_settings_file(findings: &mut Vec<Finding>) -> Result<()> {
+    let settings_path = get_config_dir()?.join(SETTINGS_FILE);
+    if !settings_path.exists() {
+        doctor_ok(&format!("{} does not exist yet; it is created when needed", settings_path.display()));
+        return Ok(());
+    }
+    let settings = match serde_json::from_str::<Value>(&fs::read_to_string(&settings_path)?) {
+        Ok(settings) => settings,
+        Err(e) => {
+            findings.push(Finding::manual(
+                format!("{} is not valid JSON: {}", settings_path.display(), e),
+                "fix the file by hand; 'settings' launch mode can't use it until then",
+            ));
+            return Ok(());
+        }
+    };
+    if !settings.is_object() {
+        findings.push(Finding::manual(
+            format!("{} does not contain a JSON object", settings_path.display()),
+            "replace its content with an object, e.g. {}",
+        ));
+        return Ok(());
+    }
+    if let Err(e) = read_settings_env(&settings, &settings_path) {
+        findings.push(Finding::manual(e.to_string(), "make \"env\" an object of variable names to values"));
+        return Ok(());
+    }
+    doctor_ok(&format!("{} is valid", settings_path.display()));
+    Ok(())
+}
+
+/// A journal means a `settings` mode session is running or was interrupted; without one, provider
+/// variables in settings.json were put there by hand or by an older version.
+fn check_leftover_injection(findings: &mut Vec<Finding>) -> Result<()> {
+    if let Some(journal) = SwapJournal::load()? {
+        if !journal.is_stale() {
+            doctor_ok(&format!("provider '{}' is active in settings.json (pid {})", journal.provider, journal.pid));
+            return Ok(());
+        }
+        findings.push(Finding::fixable(
+            format!("an interrupted session left provider '{}' in {}", journal.provider, journal.settings_path.display()),
+            "run 'claude-provider recover'",
+            move || {
+                report_conflicts(&journal.restore()?);
+                Ok(())
+            },
+        ));
+        return Ok(());
+    }
+
+    let settings_path = get_config_dir()?.join(SETTINGS_FILE);
+    let Some(settings) = fs::read_to_string(&settings_path).ok().and_then(|c| serde_json::from_str::<Value>(&c).ok())
+    else {
+        return Ok(());
+    };
+    let Ok(current_env) = read_settings_env(&settings, &settings_path) else {
+        return Ok(());
+    };
+
+    // Read without list_providers or load_provider so a plain `doctor` neither creates and migrates
+    // the providers directory nor upgrades files as a side effect.
+    let providers_dir = get_providers_dir()?;
+    for name in provider_names_in(&providers_dir)? {
+        let content = fs::read_to_string(providers_dir.join(format!("{}.json", name)))?;
+        let Ok(provider) = parse_provider_document(&content, &name) else { continue };
+        let injected: Vec<String> = build_env_object(&provider.env)
+            .into_iter()
+            .filter(|(key, value)| key.starts_with("ANTHROPIC_") && current_env.get(key) == Some(value))
+            .map(|(key, _)| key)
+            .collect();
+        if !injected.iter().any(|key| key == "ANTHROPIC_BASE_URL") {
+            continue;
+        }
+        let settings_path = settings_path.clone();
+        findings.push(Finding::fixable(
+            format!("{} still holds the variables of provider '{}': {}", settings_path.display(), name, injected.join(", ")),
+            "remove them from \"env\" so they don't override other providers",
+            move || {
+                let mut settings: Value = serde_json::from_str(&fs::read_to_string(&settings_path)?)?;
+                if let Some(env) = settings.get_mut("env").and_then(Value::as_object_mut) {
+                    for key in &injected {
+                        env.remove(key);
+                    }
+                }
+                write_atomic(&settings_path, &serde_json::to_string_pretty(&settings)?)
+            },
+        ));
+        return Ok(());
+    }
+    doctor_ok("no provider variables left over in settings.json");
+    Ok(())
+}
+
+fn check_provider_files(findings: &mut Vec<Finding>) -> Result<()> {
+    let providers_dir = get_providers_dir()?;
+    let mut checked = 0;
+    // A missing directory is created (and legacy files migrated) by the first command that needs it.
+    for entry in fs::read_dir(&providers_dir).into_iter().flatten() {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let name = path.file_stem().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+        if let Err(e) = validate_provider_name(&name) {
+            findings.push(Finding::manual(
+                format!("{} is ignored: {}", path.display(), e),
+                "rename the file or move it out of the providers directory",
+            ));
+            continue;
+        }
+        checked += 1;
+
+        let content = fs::read_to_string(&path)?;
+        let issues = provider_document_issues(&content);
+        if !issues.is_empty() {
+            findings.push(Finding::manual(
+                format!("provider '{}': {}", name, issues.join("; ")),
+                format!("run 'claude-provider edit {} --editor'", name),
+            ));
+            continue;
+        }
+
+        let version = serde_json::from_str::<Value>(&content)
+            .ok()
+            .and_then(|v| v.get("schema_version").and_then(Value::as_u64))
+            .unwrap_or(1);
+        if version < PROVIDER_SCHEMA_VERSION as u64 {
+            findings.push(Finding::fixable(
+                format!("provider '{}' uses schema version {}", name, version),
+                "it is upgraded the next time it is used",
+                move || load_provider(&name).map(|_| ()),
+            ));
+        }
+    }
+    if checked > 0 {
+        doctor_ok(&format!("{} provider file(s) in {}", checked, providers_dir.display()));
+    }
+
+    let orphaned: Vec<PathBuf> = provider_backups(&providers_dir, None)
+        .into_iter()
+        .filter(|backup| {
+            let file_name = backup.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            let name = file_name.split(".json.v").next().unwrap_or_default();
+            !providers_dir.join(format!("{}.json", name)).exists()
+        })
+        .collect();
+    if !orphaned.is_empty() {
+        let names: Vec<String> = orphaned.iter().map(|p| p.display().to_string()).collect();
+        findings.push(Finding::fixable(
+            format!("upgrade backups of removed providers: {}", names.join(", ")),
+            "delete them; they can still hold tokens",
+            move || orphaned.iter().try_for_each(|path| Ok(fs::remove_file(path)?)),
+        ));
+    }
+
+    let config_dir = get_config_dir()?;
+    if providers_dir != config_dir {
+        let legacy: Vec<PathBuf> = fs::read_dir(&config_dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|path| path.is_file() && is_legacy_provider_file(path))
+            .collect();
+        if !legacy.is_empty() {
+            let names: Vec<String> = legacy.iter().map(|p| p.display().to_string()).collect();
+            findings.push(Finding::fixable(
+                format!("provider files outside the providers directory: {}", names.join(", ")),
+                format!("move them into {}", providers_dir.display()),
+                move || {
+                    fs::create_dir_all(&providers_dir)?;
+                    migrate_legacy_providers(&providers_dir)
+                },
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Function names declared in one of our shell function files.
+fn shell_function_names(func_path: &Path) -> Vec<String> {
+    fs::read_to_string(func_path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.strip_prefix("# Provider function for "))
+        .map(|name| name.trim().to_string())
+        .collect()
+}
+
+fn check_shell_functions(findings: &mut Vec<Finding>) -> Result<()> {
+    if !ToolConfig::load()?.shell_function_files.unwrap_or(true) {
+        doctor_ok("shell functions come from 'claude-provider init'");
+        return Ok(());
+    }
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("Could not determine the home directory"))?;
+    let providers = provider_names_in(&get_providers_dir()?)?;
+
+    for shell in integrated_shells() {
+        let func_path = shell.func_path()?;
+        let functions = shell_function_names(&func_path);
+        let missing: Vec<String> = providers.iter().filter(|p| !functions.contains(p)).cloned().collect();
+        let stale: Vec<String> = functions.iter().filter(|f| !providers.contains(f)).cloned().collect();
+        let mut ok = true;
+
+        if !missing.is_empty() {
+            ok = false;
+            let names = missing.clone();
+            findings.push(Finding::fixable(
+                format!("{} has no function for: {}", func_path.display(), missing.join(", ")),
+                "recreate the functions",
+                move || names.iter().try_for_each(|name| append_provider_function(name)),
+            ));
+        }
+        if !stale.is_empty() {
+            ok = false;
+            let names = stale.clone();
+            findings.push(Finding::fixable(
+                format!("{} has functions for removed providers: {}", func_path.display(), stale.join(", ")),
+                "remove the stale functions",
+                move || names.iter().try_for_each(|name| remove_provider_function(name)),
+            ));
+        }
+        if let Some(rc_file_name) = shell.rc_file_name() {
+            let rc_path = home.join(rc_file_name);
+            let source_line = shell.source_command(&func_path);
+            let sourced = fs::read_to_string(&rc_path).is_ok_and(|rc| rc.contains(&source_line));
+            if !providers.is_empty() && !sourced && missing.is_empty() {
+                ok = false;
+                findings.push(Finding::fixable(
+                    format!("~/{} does not source {}", rc_file_name, func_path.display()),
+                    format!("add '{}' to ~/{}", source_line, rc_file_name),
+                    move || {
+                        let rc = fs::read_to_string(&rc_path).unwrap_or_default();
+                        fs::write(&rc_path, format!("{}\n{}\n", rc.trim_end(), source_line).trim_start())?;
+                        Ok(())
+                    },
+                ));
+            }
+        }
+        if ok && !providers.is_empty() {
+            doctor_ok(&format!("{} functions match the providers and are loaded", shell.name()));
+        }
+    }
+    Ok(())
+}
+
+/// Provider files may hold plaintext tokens, and the vault and journal hold secrets or copies of
+/// settings.json, so none of them should be readable by other users.
+fn check_permissions(findings: &mut Vec<Finding>) -> Result<()> {
+    let providers_dir = get_providers_dir()?;
+    let mut paths = Vec::new();
+    for name in provider_names_in(&providers_dir)? {
+        paths.push(providers_dir.join(format!("{}.json", name)));
+    }
+    paths.extend(provider_backups(&providers_dir, None));
+    paths.push(Vault::path()?);
+    paths.push(SwapJournal::path()?);
+
+    let exposed: Vec<PathBuf> = paths.into_iter().filter(|p| p.exists() && !is_private(p)).collect();
+    if exposed.is_empty() {
+        doctor_ok("secret-bearing files are private to you");
+        return Ok(());
+    }
+    let names: Vec<String> = exposed.iter().map(|p| p.display().to_string()).collect();
+    findings.push(Finding::fixable(
+        format!("readable by other users: {}", names.join(", ")),
+        "chmod 600 them",
+        // Skips files an earlier fix already deleted, such as orphaned backups.
+        move || exposed.iter().filter(|path| path.exists()).try_for_each(|path| set_private_permissions(path)),
+    ));
+    Ok(())
+}
 
-    fs::write(&settings_path, settings_content)?;
+fn doctor_command(fix: bool) -> Result<()> {
+    let mut findings = Vec::new();
 
-    enable_raw_mode().ok();
+    println!();
+    check_claude_binary(&mut findings);
+    check_settings_file(&mut findings)?;
+    check_leftover_injection(&mut findings)?;
+    check_provider_files(&mut findings)?;
+    check_shell_functions(&mut findings)?;
+    check_permissions(&mut findings)?;
 
-    if !status.success() {
-        return Err(anyhow!("claude exited with non-zero status"));
+    let mut remaining = 0;
+    for finding in findings {
+        println!("  ✗ {}", finding.problem);
+        match finding.fix {
+            Some(apply) if fix => match apply() {
+                Ok(()) => println!("      ✓ fixed"),
+                Err(e) => {
+                    println!("      ✗ fix failed: {:#}", e);
+                    remaining += 1;
+                }
+            },
+            Some(_) => {
+                println!("      → {} (or run 'claude-provider doctor --fix')", finding.hint);
+                remaining += 1;
+            }
+            None => {
+                println!("      → {}", finding.hint);
+                remaining += 1;
+            }
+        }
     }
+    println!();
 
+    if remaining > 0 {
+        return Err(anyhow!("{} problem(s) found", remaining));
+    }
     Ok(())
 }
 
@@ -565,13 +4122,175 @@ fn list_providers_command() -> Result<()> {
     Ok(())
 }
 
+fn init_command(shell: Shell) -> Result<()> {
+    let functions: Vec<String> = list_providers()?.iter().map(|name| shell.function_definition(name)).collect();
+    print!("{}", functions.join("\n"));
+    Ok(())
+}
+
+/// Provider names for completion. Never creates the providers directory, so completing can't
+/// trigger a migration.
+fn complete_provider_names(current: &std::ffi::OsStr) -> Vec<CompletionCandidate> {
+    let Some(prefix) = current.to_str() else {
+        return Vec::new();
+    };
+    if !get_providers_dir().is_ok_and(|dir| dir.is_dir()) {
+        return Vec::new();
+    }
+    list_providers()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+/// Prints clap's registration script, which calls back into claude-provider with COMPLETE set, then
+/// lets the provider functions complete like `claude`. Fish functions get that from `--wraps claude`.
+fn completions_command(shell: Shell) -> Result<()> {
+    let shells = Shells::builtins();
+    let completer = shells
+        .completer(shell.name())
+        .ok_or_else(|| anyhow!("Completions are available for bash, zsh and fish, not {}", shell.name()))?;
+    let mut script = Vec::new();
+    completer.write_registration("COMPLETE", "claude-provider", "claude-provider", "claude-provider", &mut script)?;
+    io::stdout().write_all(&script)?;
+
+    let providers = list_providers()?;
+    if providers.is_empty() {
+        return Ok(());
+    }
+    match shell {
+        Shell::Bash => {
+            println!("if [[ $(type -t _completion_loader) == function ]]; then _completion_loader claude 2>/dev/null; fi");
+            println!(
+                "_claude_provider_spec=$(complete -p claude 2>/dev/null) && eval \"${{_claude_provider_spec% claude}} {}\"",
+                providers.join(" ")
+            );
+            println!("unset _claude_provider_spec");
+        }
+        Shell::Zsh => {
+            let pairs: Vec<String> = providers.iter().map(|name| format!("{}=claude", name)).collect();
+            println!("(( $+functions[compdef] )) && compdef {}", pairs.join(" "));
+        }
+        Shell::Fish | Shell::Nushell => {}
+    }
+    Ok(())
+}
+
+fn config_command(key: Option<String>, value: Option<String>) -> Result<()> {
+    let mut config = ToolConfig::load()?;
+
+    let Some(key) = key else {
+        println!();
+        println!("  {:<20} = {}", "launch-mode", config.launch_mode.unwrap_or_default().as_str());
+        println!("  {:<20} = {}", "env-merge", config.env_merge.unwrap_or_default().as_str());
+        println!("  {:<20} = {}", "secret-feedback", config.secret_feedback.unwrap_or_default().as_str());
+        println!("  {:<20} = {}", "secret-cache-ttl", config.secret_cache_ttl.unwrap_or(0));
+        println!("  {:<20} = {}", "providers-dir", get_providers_dir()?.display());
+        println!("  {:<20} = {}", "migration-backups", config.migration_backups.unwrap_or(true));
+        println!("  {:<20} = {}", "shell-function-files", config.shell_function_files.unwrap_or(true));
+        println!();
+        return Ok(());
+    };
+
+    match (key.as_str(), value) {
+        ("launch-mode", None) => {
+            println!("{}", config.launch_mode.unwrap_or_default().as_str());
+        }
+        ("launch-mode", Some(value)) => {
+            let mode = LaunchMode::from_str(&value, true).map_err(|_| {
+                anyhow!("Invalid launch mode '{}' (expected 'env' or 'settings')", value)
+            })?;
+            config.launch_mode = Some(mode);
+            config.save()?;
+            println!("  ✓ Default launch mode set to '{}'", mode.as_str());
+        }
+        ("env-merge", None) => {
+            println!("{}", config.env_merge.unwrap_or_default().as_str());
+        }
+        ("env-merge", Some(value)) => {
+            let strategy = EnvMergeStrategy::from_str(&value, true).map_err(|_| {
+                anyhow!("Invalid env merge strategy '{}' (expected 'replace', 'merge' or 'merge-preserve')", value)
+            })?;
+            config.env_merge = Some(strategy);
+            config.save()?;
+            println!("  ✓ Default env merge strategy set to '{}'", strategy.as_str());
+        }
+        ("secret-feedback", None) => {
+            println!("{}", config.secret_feedback.unwrap_or_default().as_str());
+        }
+        ("secret-feedback", Some(value)) => {
+            let feedback = SecretFeedback::from_str(&value, true).map_err(|_| {
+                anyhow!("Invalid secret feedback '{}' (expected 'hidden' or 'asterisks')", value)
+            })?;
+            config.secret_feedback = Some(feedback);
+            config.save()?;
+            println!("  ✓ Secret input feedback set to '{}'", feedback.as_str());
+        }
+        ("secret-cache-ttl", None) => {
+            println!("{}", config.secret_cache_ttl.unwrap_or(0));
+        }
+        ("secret-cache-ttl", Some(value)) => {
+            let ttl: u64 = value
+                .parse()
+                .map_err(|_| anyhow!("Invalid secret cache TTL '{}' (expected a number of seconds)", value))?;
+            config.secret_cache_ttl = Some(ttl).filter(|ttl| *ttl > 0);
+            config.save()?;
+            println!("  ✓ Secret command cache TTL set to {}s", ttl);
+        }
+        ("migration-backups", None) => {
+            println!("{}", config.migration_backups.unwrap_or(true));
+        }
+        ("migration-backups", Some(value)) => {
+            let enabled: bool = value
+                .parse()
+                .map_err(|_| anyhow!("Invalid migration-backups '{}' (expected 'true' or 'false')", value))?;
+            config.migration_backups = Some(enabled);
+            config.save()?;
+            println!("  ✓ Backups before provider upgrades {}", if enabled { "enabled" } else { "disabled" });
+        }
+        ("shell-function-files", None) => {
+            println!("{}", config.shell_function_files.unwrap_or(true));
+        }
+        ("shell-function-files", Some(value)) => {
+            let enabled: bool = value
+                .parse()
+                .map_err(|_| anyhow!("Invalid shell-function-files '{}' (expected 'true' or 'false')", value))?;
+            config.shell_function_files = Some(enabled);
+            config.save()?;
+            if enabled {
+                println!("  ✓ Shell function files enabled; run 'claude-provider doctor --fix' to create them");
+            } else {
+                println!("  ✓ Shell function files disabled; load the functions with 'claude-provider init <shell>'");
+                println!("  Existing function files and rc source lines were left in place.");
+            }
+        }
+        ("providers-dir", None) => {
+            println!("{}", get_providers_dir()?.display());
+        }
+        ("providers-dir", Some(value)) => {
+            let previous = get_providers_dir()?;
+            let had_providers = previous.is_dir()
+                && fs::read_dir(&previous)?.any(|e| e.is_ok_and(|e| e.path().extension().is_some_and(|x| x == "json")));
+            config.providers_dir = Some(value).filter(|v| v != "default");
+            config.save()?;
+            let dir = get_providers_dir()?;
+            println!("  ✓ Providers directory set to {}", dir.display());
+            if dir != previous && had_providers {
+                println!("  Existing providers in {} were not moved.", previous.display());
+            }
+        }
+        (other, _) => return Err(anyhow!("Unknown config key '{}'", other)),
+    }
+
+    Ok(())
+}
+
 fn detect_shell_command() -> Result<()> {
     let shell = detect_shell();
     println!();
-    match shell {
-        Shell::Bash => println!("  Detected shell: bash"),
-        Shell::Zsh => println!("  Detected shell: zsh"),
-    }
+    println!("  Detected shell: {}", shell.name());
     println!();
     Ok(())
 }
@@ -590,31 +4309,363 @@ struct Args {
 enum Commands {
     Setup,
 
-    Remove,
+    /// Create a provider non-interactively (for provisioning scripts)
+    Add {
+        /// Provider name; may be omitted with --from-json if the document has a "name" field
+        name: Option<String>,
+
+        #[arg(long)]
+        base_url: Option<String>,
+
+        /// API key or secret reference such as env:NAME, file:PATH or cmd:COMMAND
+        #[arg(long, conflicts_with = "token_stdin")]
+        token: Option<String>,
+
+        /// Read the API key from stdin
+        #[arg(long)]
+        token_stdin: bool,
+
+        /// Default model; also used for sonnet and opus unless those are given
+        #[arg(long)]
+        model: Option<String>,
+
+        #[arg(long)]
+        sonnet_model: Option<String>,
+
+        #[arg(long)]
+        opus_model: Option<String>,
+
+        #[arg(long)]
+        haiku_model: Option<String>,
+
+        /// Background model; defaults to the haiku model, then the default model
+        #[arg(long)]
+        small_fast_model: Option<String>,
+
+        /// Extra environment variable for the provider (repeatable)
+        #[arg(long = "env", value_name = "KEY=VALUE")]
+        env: Vec<String>,
+
+        #[arg(long, value_enum)]
+        launch_mode: Option<LaunchMode>,
+
+        #[arg(long, value_enum)]
+        env_merge: Option<EnvMergeStrategy>,
+
+        /// Read a full provider document from a file, or '-' for stdin
+        #[arg(long, value_name = "FILE")]
+        from_json: Option<String>,
+
+        /// Overwrite an existing provider with the same name
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Change an existing provider with a prefilled form
+    Edit {
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
+        name: String,
+
+        /// Open the provider JSON in $VISUAL/$EDITOR instead, validating it before saving
+        #[arg(long)]
+        editor: bool,
+    },
+
+    /// Set one field of a provider (token '-' reads stdin)
+    Set {
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
+        name: String,
+
+        /// base-url, token, model, sonnet-model, opus-model, haiku-model, small-fast-model, timeout-ms,
+        /// disable-nonessential-traffic, thinking, launch-mode, env-merge, env.<NAME> or settings.<key>
+        field: String,
+
+        value: String,
+    },
+
+    /// Clear one field of a provider
+    Unset {
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
+        name: String,
+
+        field: String,
+    },
+
+    /// Duplicate a provider under a new name
+    Copy {
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
+        src: String,
+
+        dst: String,
+
+        /// Overwrite an existing provider with the destination name
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Rename a provider and its shell functions
+    Rename {
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
+        old: String,
+
+        new: String,
+
+        /// Overwrite an existing provider with the new name
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Save the "env" of settings.json as a new provider
+    Capture {
+        name: String,
+
+        /// Overwrite an existing provider with this name
+        #[arg(long)]
+        force: bool,
+
+        /// Remove the captured variables from settings.json without asking
+        #[arg(long)]
+        strip: bool,
+    },
+
+    /// Remove providers by name or glob; opens a menu when no name is given
+    Remove {
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
+        names: Vec<String>,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Write providers to a versioned bundle file
+    Export {
+        /// Providers to export, by name or glob (default: all)
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
+        names: Vec<String>,
+
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// What to write in place of plaintext tokens
+        #[arg(long, value_enum, default_value_t)]
+        tokens: TokenExport,
+    },
+
+    /// Add the providers of a bundle file ('-' for stdin)
+    Import {
+        file: String,
+
+        /// What to do when a provider already exists (asks when not given)
+        #[arg(long, value_enum)]
+        on_conflict: Option<ImportConflict>,
+    },
 
     List,
 
     Detect,
 
+    /// Print the provider functions for a shell, e.g. eval "$(claude-provider init zsh)"
+    Init {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+
+    /// Print a completion script for a shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+
     Use {
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
         provider: String,
 
+        /// Override the launch mode configured for the provider or globally
+        #[arg(long, value_enum)]
+        mode: Option<LaunchMode>,
+
         #[arg(trailing_var_arg = true)]
         args: Vec<String>,
     },
 
+    /// Show or change global settings (e.g. `config launch-mode settings`)
+    Config {
+        key: Option<String>,
+
+        value: Option<String>,
+    },
+
+    /// List the settings.json env variables a provider would override or drop
+    Overrides {
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
+        provider: String,
+
+        /// Preview a different merge strategy than the configured one
+        #[arg(long, value_enum)]
+        strategy: Option<EnvMergeStrategy>,
+    },
+
+    /// Manage the encrypted vault for provider credentials
+    Vault {
+        #[command(subcommand)]
+        command: VaultCommands,
+    },
+
+    /// Check that providers are reachable, accept their token and serve their models
+    Test {
+        #[arg(add = ArgValueCompleter::new(complete_provider_names))]
+        names: Vec<String>,
+
+        /// Test every configured provider
+        #[arg(long, conflicts_with = "names")]
+        all: bool,
+    },
+
+    /// Check the installation for problems
+    Doctor {
+        /// Apply the safe automatic fixes
+        #[arg(long)]
+        fix: bool,
+    },
+
+    /// Restore settings.json after a settings-mode session was interrupted
+    Recover {
+        /// Restore even if the recorded session still appears to be running
+        #[arg(long)]
+        force: bool,
+    },
+
     Interactive,
 }
 
+#[derive(Subcommand, Debug)]
+enum VaultCommands {
+    /// Create an empty vault protected by a passphrase or key file
+    Init {
+        #[arg(long, value_name = "PATH")]
+        key_file: Option<PathBuf>,
+    },
+
+    /// Add or replace a secret; reference it from a provider as vault:<ENTRY>
+    Add {
+        entry: String,
+
+        /// Read the secret from stdin instead of prompting
+        #[arg(long)]
+        stdin: bool,
+    },
+
+    /// List entry names
+    List,
+
+    /// Re-encrypt the vault with a new passphrase, or with a key file
+    RotatePassphrase {
+        #[arg(long, value_name = "PATH")]
+        key_file: Option<PathBuf>,
+    },
+
+    /// Print all secrets as unencrypted JSON
+    Export {
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Move plaintext tokens from provider files into the vault
+    Migrate {
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
 fn main() -> Result<()> {
+    // Answers the shell when it calls back for completions (see `completions`).
+    CompleteEnv::with_factory(Args::command).complete();
+
     let args = Args::parse();
 
+    // Recover and doctor report an interrupted session themselves, and the output of init and
+    // completions is eval'd by the shell, so a prompt there would be run as code.
+    if !matches!(
+        args.command,
+        Commands::Recover { .. } | Commands::Doctor { .. } | Commands::Init { .. } | Commands::Completions { .. }
+    )
+        && let Err(e) = recover_stale_session()
+    {
+        eprintln!("Error: {:#}", e);
+    }
+
     match args.command {
         Commands::Setup => {
             setup_provider_interactive()?;
         }
-        Commands::Remove => {
-            remove_provider_interactive()?;
+        Commands::Add {
+            name,
+            base_url,
+            token,
+            token_stdin,
+            model,
+            sonnet_model,
+            opus_model,
+            haiku_model,
+            small_fast_model,
+            env,
+            launch_mode,
+            env_merge,
+            from_json,
+            force,
+        } => {
+            add_provider_command(AddOptions {
+                name,
+                base_url,
+                token,
+                token_stdin,
+                model,
+                sonnet_model,
+                opus_model,
+                haiku_model,
+                small_fast_model,
+                env,
+                launch_mode,
+                env_merge,
+                from_json,
+                force,
+            })?;
+        }
+        Commands::Edit { name, editor } => {
+            if editor {
+                edit_provider_in_editor(&name)?;
+            } else {
+                edit_provider_interactive(&name)?;
+            }
+        }
+        Commands::Set { name, field, value } => {
+            set_field_command(&name, &field, Some(value))?;
+        }
+        Commands::Unset { name, field } => {
+            set_field_command(&name, &field, None)?;
+        }
+        Commands::Copy { src, dst, force } => {
+            copy_provider_command(&src, &dst, force)?;
+        }
+        Commands::Rename { old, new, force } => {
+            rename_provider_command(&old, &new, force)?;
+        }
+        Commands::Capture { name, force, strip } => {
+            capture_command(&name, force, strip)?;
+        }
+        Commands::Remove { names, yes } => {
+            if names.is_empty() {
+                remove_provider_interactive()?;
+            } else {
+                remove_providers_command(&names, yes)?;
+            }
+        }
+        Commands::Export { names, output, tokens } => {
+            export_command(&names, output, tokens)?;
+        }
+        Commands::Import { file, on_conflict } => {
+            import_command(&file, on_conflict)?;
         }
         Commands::List => {
             list_providers_command()?;
@@ -622,11 +4673,37 @@ fn main() -> Result<()> {
         Commands::Detect => {
             detect_shell_command()?;
         }
-        Commands::Use { provider, args } => {
-            enable_raw_mode().context("Failed to enable raw mode")?;
-            let result = run_with_provider(&provider, &args);
-            disable_raw_mode().ok();
-            result?;
+        Commands::Init { shell } => {
+            init_command(shell)?;
+        }
+        Commands::Completions { shell } => {
+            completions_command(shell)?;
+        }
+        Commands::Config { key, value } => {
+            config_command(key, value)?;
+        }
+        Commands::Overrides { provider, strategy } => {
+            overrides_command(&provider, strategy)?;
+        }
+        Commands::Vault { command } => match command {
+            VaultCommands::Init { key_file } => vault_init_command(key_file)?,
+            VaultCommands::Add { entry, stdin } => vault_add_command(&entry, stdin)?,
+            VaultCommands::List => vault_list_command()?,
+            VaultCommands::RotatePassphrase { key_file } => vault_rotate_command(key_file)?,
+            VaultCommands::Export { output } => vault_export_command(output)?,
+            VaultCommands::Migrate { yes } => vault_migrate_command(yes)?,
+        },
+        Commands::Test { names, all } => {
+            test_command(&names, all)?;
+        }
+        Commands::Recover { force } => {
+            recover_command(force)?;
+        }
+        Commands::Doctor { fix } => {
+            doctor_command(fix)?;
+        }
+        Commands::Use { provider, mode, args } => {
+            run_with_provider(&provider, &args, mode)?;
         }
         Commands::Interactive => {
             clear_screen();
@@ -641,6 +4718,7 @@ fn main() -> Result<()> {
             loop {
                 let options = vec![
                     "Setup a new provider",
+                    "Edit a provider",
                     "Remove a provider",
                     "List providers",
                     "Exit",
@@ -664,7 +4742,7 @@ fn main() -> Result<()> {
                     }
                     1 => {
                         drop(_raw_guard);
-                        if let Err(e) = remove_provider_interactive() {
+                        if let Err(e) = pick_and_edit_provider_interactive() {
                             eprintln!("Error: {:#}", e);
                             wait_for_key();
                         }
@@ -677,6 +4755,20 @@ fn main() -> Result<()> {
                         };
                     }
                     2 => {
+                        drop(_raw_guard);
+                        if let Err(e) = remove_provider_interactive() {
+                            eprintln!("Error: {:#}", e);
+                            wait_for_key();
+                        }
+                        _raw_guard = match RawModeGuard::new() {
+                            Ok(g) => g,
+                            Err(e) => {
+                                eprintln!("Failed to restore raw mode: {:#}", e);
+                                return Err(e);
+                            }
+                        };
+                    }
+                    3 => {
                         drop(_raw_guard);
                         let _ = list_providers_command();
                         wait_for_key();
@@ -688,7 +4780,7 @@ fn main() -> Result<()> {
                             }
                         };
                     }
-                    3 | _ => {
+                    _ => {
                         drop(_raw_guard);
                         clear_screen();
                         break;
@@ -700,3 +4792,346 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+
+    /// Serves one canned response per connection on a local port and returns its base URL.
+    /// `respond` gets the request line and body and returns the status, content type and body.
+    fn mock_server(respond: impl Fn(&str, &str) -> (u16, &'static str, String) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                let (status, content_type, body) = respond(&request_line, &String::from_utf8_lossy(&body));
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    content_type,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        base_url
+    }
+
+    fn test_agent() -> ureq::Agent {
+        ureq::AgentBuilder::new().timeout(API_TIMEOUT).build()
+    }
+
+    fn api_error(kind: &str, message: &str) -> String {
+        serde_json::json!({ "type": "error", "error": { "type": kind, "message": message } }).to_string()
+    }
+
+    #[test]
+    fn probe_accepts_a_successful_response() {
+        let base_url = mock_server(|request, body| {
+            assert!(request.starts_with("POST /v1/messages "));
+            assert!(body.contains("\"model\":\"m-good\""));
+            (200, "application/json", r#"{"type":"message","content":[]}"#.to_string())
+        });
+        let result = probe_messages(&test_agent(), &base_url, "good", "m-good", false);
+        assert_eq!(result.status, Some(200));
+        assert_eq!(result.error, None);
+    }
+
+    #[test]
+    fn probe_reports_authentication_failures() {
+        let base_url =
+            mock_server(|_, _| (401, "application/json", api_error("authentication_error", "invalid x-api-key")));
+        let result = probe_messages(&test_agent(), &base_url, "bad", "m-good", false);
+        assert_eq!(result.status, Some(401));
+        assert_eq!(result.error.as_deref(), Some("authentication failed: invalid x-api-key"));
+    }
+
+    #[test]
+    fn probe_tells_unknown_models_from_wrong_endpoints() {
+        let base_url = mock_server(|_, _| (404, "application/json", api_error("not_found_error", "model: m-nope")));
+        let result = probe_messages(&test_agent(), &base_url, "good", "m-nope", false);
+        assert_eq!(result.status, Some(404));
+        assert_eq!(result.error.as_deref(), Some("unknown model: model: m-nope"));
+
+        let base_url = mock_server(|_, _| (404, "text/plain", "Not Found".to_string()));
+        let result = probe_messages(&test_agent(), &base_url, "good", "m-good", false);
+        assert_eq!(result.error.as_deref(), Some("endpoint not found, check the base URL: Not Found"));
+    }
+
+    #[test]
+    fn streaming_probe_waits_for_the_first_event() {
+        let base_url = mock_server(|_, body| {
+            assert!(body.contains("\"stream\":true"));
+            (200, "text/event-stream", "event: message_start\ndata: {\"type\":\"message_start\"}\n\n".to_string())
+        });
+        let result = probe_messages(&test_agent(), &base_url, "good", "m-good", true);
+        assert_eq!(result.status, Some(200));
+        assert_eq!(result.error, None);
+    }
+
+    #[test]
+    fn streaming_probe_rejects_non_stream_responses() {
+        let base_url = mock_server(|_, _| (200, "application/json", r#"{"type":"message"}"#.to_string()));
+        let result = probe_messages(&test_agent(), &base_url, "good", "m-good", true);
+        assert_eq!(result.error.as_deref(), Some("expected an event stream, got 'application/json'"));
+
+        let base_url = mock_server(|_, _| (200, "text/event-stream", String::new()));
+        let result = probe_messages(&test_agent(), &base_url, "good", "m-good", true);
+        assert_eq!(result.error.as_deref(), Some("stream ended before the first event"));
+    }
+
+    #[test]
+    fn probe_reports_connection_failures() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let result = probe_messages(&test_agent(), &format!("http://127.0.0.1:{}", port), "good", "m-good", false);
+        assert_eq!(result.status, None);
+        assert!(result.error.unwrap().starts_with("connection failed"));
+    }
+
+    #[test]
+    fn list_models_follows_pagination() {
+        let base_url = mock_server(|request, _| {
+            assert!(request.starts_with("GET /v1/models?"));
+            let page = if request.contains("after_id=m-b") {
+                serde_json::json!({ "data": [{ "id": "m-c" }], "has_more": false, "last_id": "m-c" })
+            } else {
+                serde_json::json!({ "data": [{ "id": "m-a" }, { "id": "m-b" }], "has_more": true, "last_id": "m-b" })
+            };
+            (200, "application/json", page.to_string())
+        });
+        let models = list_models(&test_agent(), &format!("{}/", base_url), "good").unwrap();
+        assert_eq!(models, ["m-a", "m-b", "m-c"]);
+    }
+
+    #[test]
+    fn list_models_explains_missing_endpoints_and_bad_tokens() {
+        let base_url = mock_server(|_, _| (404, "text/plain", "Not Found".to_string()));
+        let error = list_models(&test_agent(), &base_url, "good").unwrap_err();
+        assert_eq!(error.to_string(), "the provider does not support listing models");
+
+        let base_url = mock_server(|_, _| (401, "application/json", api_error("authentication_error", "bad token")));
+        let error = list_models(&test_agent(), &base_url, "bad").unwrap_err();
+        assert_eq!(error.to_string(), "authentication failed: bad token");
+    }
+
+    fn path(path: &str) -> Vec<String> {
+        path.split('.').map(str::to_string).collect()
+    }
+
+    /// Runs a session: injects `changes` into `before`, lets `during` edit the result like claude or
+    /// the user would, then reverts.
+    fn run_session(
+        before: Value,
+        changes: &[(&str, Option<Value>)],
+        during: impl FnOnce(&mut Value),
+    ) -> (Value, Vec<String>) {
+        let mut patch = SettingsPatch::default();
+        for (key, injected) in changes {
+            patch.set(&before, path(key), injected.clone());
+        }
+        let mut settings = before;
+        patch.apply(&mut settings);
+        during(&mut settings);
+        let conflicts = patch.revert(&mut settings);
+        (settings, conflicts)
+    }
+
+    #[test]
+    fn revert_restores_untouched_injected_values() {
+        let before = serde_json::json!({ "env": { "ANTHROPIC_MODEL": "mine", "KEEP": "1" }, "model": "opus" });
+        let (after, conflicts) = run_session(
+            before.clone(),
+            &[("env.ANTHROPIC_MODEL", Some("theirs".into())), ("env.ANTHROPIC_BASE_URL", Some("https://x".into()))],
+            |settings| assert_eq!(settings["env"]["ANTHROPIC_MODEL"], "theirs"),
+        );
+        assert_eq!(after, before);
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn revert_keeps_values_edited_during_the_session() {
+        let before = serde_json::json!({ "env": { "ANTHROPIC_MODEL": "mine" } });
+        let (after, conflicts) = run_session(
+            before,
+            &[("env.ANTHROPIC_MODEL", Some("theirs".into()))],
+            |settings| {
+                settings["env"]["ANTHROPIC_MODEL"] = "edited".into();
+                settings["permissions"] = serde_json::json!({ "allow": ["Bash(ls)"] });
+            },
+        );
+        assert_eq!(after["env"]["ANTHROPIC_MODEL"], "edited");
+        assert_eq!(after["permissions"]["allow"][0], "Bash(ls)");
+        assert_eq!(conflicts, ["env.ANTHROPIC_MODEL"]);
+    }
+
+    #[test]
+    fn revert_respects_keys_removed_during_the_session() {
+        let before = serde_json::json!({ "env": { "ANTHROPIC_MODEL": "mine", "KEEP": "1" } });
+        let (after, conflicts) = run_session(
+            before,
+            &[("env.ANTHROPIC_MODEL", Some("theirs".into())), ("env.ANTHROPIC_BASE_URL", Some("https://x".into()))],
+            |settings| {
+                let env = settings["env"].as_object_mut().unwrap();
+                env.remove("ANTHROPIC_MODEL");
+                env.remove("ANTHROPIC_BASE_URL");
+            },
+        );
+        // The removed key had a value before the session, so removing it was a deliberate edit; the
+        // one the session added is simply gone, as it would have been anyway.
+        assert_eq!(after, serde_json::json!({ "env": { "KEEP": "1" } }));
+        assert_eq!(conflicts, ["env.ANTHROPIC_MODEL"]);
+    }
+
+    #[test]
+    fn revert_restores_keys_the_session_removed() {
+        let before = serde_json::json!({ "env": { "HTTPS_PROXY": "http://proxy", "KEEP": "1" } });
+        let (after, conflicts) = run_session(before.clone(), &[("env.HTTPS_PROXY", None)], |settings| {
+            assert!(settings["env"].get("HTTPS_PROXY").is_none());
+        });
+        assert_eq!(after, before);
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn revert_drops_objects_the_session_created_once_empty() {
+        let before = serde_json::json!({ "model": "opus" });
+        let (after, conflicts) = run_session(
+            before.clone(),
+            &[("env.ANTHROPIC_MODEL", Some("theirs".into())), ("enabledPlugins.p@m", Some(true.into()))],
+            |_| {},
+        );
+        assert_eq!(after, before);
+        assert!(conflicts.is_empty());
+
+        // An object that gained other keys during the session is kept for them.
+        let (after, _) = run_session(before, &[("env.ANTHROPIC_MODEL", Some("theirs".into()))], |settings| {
+            settings["env"]["ADDED"] = "by claude".into();
+        });
+        assert_eq!(after, serde_json::json!({ "model": "opus", "env": { "ADDED": "by claude" } }));
+    }
+
+    fn env_map(value: Value) -> serde_json::Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    fn merge_env(strategy: EnvMergeStrategy) -> EnvMergePlan {
+        let current = env_map(serde_json::json!({ "SHARED": "same", "CONFLICT": "user", "OWN": "user" }));
+        let provider = env_map(serde_json::json!({ "SHARED": "same", "CONFLICT": "provider", "NEW": "provider" }));
+        plan_env_merge(&current, provider, strategy)
+    }
+
+    #[test]
+    fn merge_lets_the_provider_win_conflicts() {
+        let plan = merge_env(EnvMergeStrategy::Merge);
+        assert_eq!(
+            plan.set,
+            env_map(serde_json::json!({ "SHARED": "same", "CONFLICT": "provider", "NEW": "provider" }))
+        );
+        assert_eq!(plan.overridden, ["CONFLICT"]);
+        assert!(plan.removed.is_empty());
+        assert!(plan.preserved.is_empty());
+    }
+
+    #[test]
+    fn merge_preserve_lets_existing_values_win_conflicts() {
+        let plan = merge_env(EnvMergeStrategy::MergePreserve);
+        assert_eq!(plan.set, env_map(serde_json::json!({ "NEW": "provider" })));
+        assert_eq!(plan.preserved, ["CONFLICT"]);
+        assert!(plan.removed.is_empty());
+        assert!(plan.overridden.is_empty());
+    }
+
+    #[test]
+    fn replace_drops_keys_the_provider_does_not_set() {
+        let plan = merge_env(EnvMergeStrategy::Replace);
+        assert_eq!(
+            plan.set,
+            env_map(serde_json::json!({ "SHARED": "same", "CONFLICT": "provider", "NEW": "provider" }))
+        );
+        assert_eq!(plan.removed, ["OWN"]);
+        assert_eq!(plan.overridden, ["CONFLICT"]);
+        assert!(plan.preserved.is_empty());
+    }
+
+    #[test]
+    fn upgrade_migrates_v1_upper_case_env_keys() {
+        let mut doc = serde_json::json!({ "env": {
+            "ANTHROPIC_BASE_URL": "https://x",
+            "API_TIMEOUT_MS": 3000,
+            "CLAUDE_CODE_DISABLE_NONESSENTIAL_TRAFFIC": "1",
+            "HTTPS_PROXY": "http://proxy",
+        } });
+        assert_eq!(upgrade_provider_document(&mut doc, "test").unwrap(), 1);
+        assert_eq!(
+            doc,
+            serde_json::json!({ "schema_version": PROVIDER_SCHEMA_VERSION, "env": {
+                "anthropic_base_url": "https://x",
+                "api_timeout_ms": "3000",
+                "claude_code_disable_nonessential_traffic": 1,
+                "HTTPS_PROXY": "http://proxy",
+            } })
+        );
+    }
+
+    #[test]
+    fn upgrade_keeps_existing_snake_case_env_keys() {
+        let mut doc = serde_json::json!({ "env": { "anthropic_model": "kept", "ANTHROPIC_MODEL": "stale" } });
+        upgrade_provider_document(&mut doc, "test").unwrap();
+        assert_eq!(doc["env"]["anthropic_model"], "kept");
+        assert_eq!(doc["env"]["ANTHROPIC_MODEL"], "stale");
+    }
+
+    #[test]
+    fn upgrade_leaves_current_documents_alone() {
+        let original = serde_json::json!({ "schema_version": PROVIDER_SCHEMA_VERSION, "env": { "ANTHROPIC_MODEL": "extra" } });
+        let mut doc = original.clone();
+        assert_eq!(upgrade_provider_document(&mut doc, "test").unwrap(), PROVIDER_SCHEMA_VERSION);
+        assert_eq!(doc, original);
+    }
+
+    #[test]
+    fn upgrade_refuses_newer_and_invalid_versions() {
+        let mut newer = serde_json::json!({ "schema_version": PROVIDER_SCHEMA_VERSION + 1 });
+        let err = upgrade_provider_document(&mut newer, "test").unwrap_err().to_string();
+        assert!(err.contains("uses provider schema version"), "{}", err);
+
+        for invalid in [serde_json::json!(0), serde_json::json!(-1), serde_json::json!("2"), serde_json::json!(1.5)] {
+            let mut doc = serde_json::json!({ "schema_version": invalid });
+            let err = upgrade_provider_document(&mut doc, "test").unwrap_err().to_string();
+            assert!(err.contains("invalid schema_version"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn document_check_flags_unknown_top_level_keys() {
+        let (settings, issues) = check_provider_document(
+            r#"{ "env": {}, "launch_mode": "env", "model": "opus", "launchMode": "env", "evn": {} }"#,
+        );
+        assert!(settings.is_some());
+        assert_eq!(issues, ["Unknown field 'evn'", "Unknown field 'launchMode', did you mean 'launch_mode'?"]);
+    }
+}